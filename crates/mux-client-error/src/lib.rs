@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, path::PathBuf, process::ExitStatus};
 use thiserror::Error as ThisError;
 
 pub use ssh_format_error::Error as SshFormatError;
@@ -22,6 +22,10 @@ pub enum Error {
     #[error("Server response with pid = 0.")]
     InvalidPid,
 
+    /// Server sent a message of unsupported type {0:#x}.
+    #[error("Server sent a message of unsupported type {0:#x}.")]
+    UnsupportedResponse(u32),
+
     /// Server response with a different id than the requested one.
     #[error("Server response with a different id than the requested one.")]
     UnmatchedRequestId,
@@ -45,4 +49,24 @@ pub enum Error {
     /// Server refused the request due to insufficient permission: {0}.
     #[error("Server refused the request due to insufficient permission: {0}.")]
     PermissionDenied(Box<str>),
+
+    /// Invalid `ControlPath`: {0}.
+    #[error("Invalid `ControlPath`: {0}.")]
+    InvalidControlPath(Box<str>),
+
+    /// The ssh multiplex master exited before it is ready: {0}.
+    #[error("The ssh multiplex master exited before it is ready: {0}.")]
+    MasterExited(ExitStatus),
+
+    /// The control socket {0:?} already exists.
+    #[error("The control socket {0:?} already exists.")]
+    ControlPathExists(PathBuf),
+
+    /// Timed out waiting for the ssh multiplex master to be ready.
+    #[error("Timed out waiting for the ssh multiplex master to be ready.")]
+    MasterLaunchTimeout,
+
+    /// Invalid remote command: {0}.
+    #[error("Invalid remote command: {0}.")]
+    InvalidCommand(Box<str>),
 }
@@ -0,0 +1,384 @@
+#![forbid(unsafe_code)]
+
+//! Blocking version of [`Connection`](crate::Connection) over
+//! [`std::os::unix::net::UnixStream`], which does not require an async runtime.
+
+use crate::{
+    blocking_io,
+    connection::{is_closed_by_master, subsystem_session},
+    proto::{BatchResults, Event, Protocol, Reply},
+    Error, ErrorExt, Extension, ForwardRequest, ForwardType, NonZeroByteSlice, Result, Session,
+    SessionEvent, Socket,
+};
+
+use std::{
+    convert::TryInto,
+    io::ErrorKind,
+    num::NonZeroU32,
+    os::unix::{
+        io::{AsFd, AsRawFd, RawFd},
+        net::UnixStream,
+    },
+    path::Path,
+};
+
+/// Blocking counterpart of [`Connection`](crate::Connection).
+#[derive(Debug)]
+pub struct Connection {
+    raw_conn: UnixStream,
+    /// Boxed to keep the `Err` returned by [`EstablishedSession::wait`] small.
+    protocol: Box<Protocol>,
+}
+impl Connection {
+    /// Send everything queued in `self.protocol`.
+    ///
+    /// * `fds` - fds of the new session, if any.
+    fn flush(&mut self, fds: &[RawFd]) -> Result<()> {
+        blocking_io::flush(&self.raw_conn, &mut self.protocol, fds)
+    }
+
+    fn next_event(&mut self) -> Result<Event> {
+        blocking_io::next_event(&self.raw_conn, &mut self.protocol)
+    }
+
+    /// Send the request queued in `self.protocol` with `request_id`
+    /// and wait for its reply.
+    fn wait_for_reply(&mut self, request_id: u32, fds: &[RawFd]) -> Result<Reply> {
+        blocking_io::wait_for_reply(&self.raw_conn, &mut self.protocol, request_id, fds)
+    }
+
+    fn wait_for_ok(&mut self, request_id: u32) -> Result<()> {
+        blocking_io::wait_for_ok(&self.raw_conn, &mut self.protocol, request_id)
+    }
+
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::connect_with_extensions(path, &[])
+    }
+
+    /// Same as [`Connection::connect`], but sends `extensions` as
+    /// `(name, value)` pairs in the hello message.
+    ///
+    /// See [`crate::Connection::connect_with_extensions`] for details.
+    pub fn connect_with_extensions<P: AsRef<Path>>(
+        path: P,
+        extensions: &[(&str, &str)],
+    ) -> Result<Self> {
+        let (raw_conn, protocol) = blocking_io::connect(path, extensions)?;
+
+        Ok(Self { raw_conn, protocol })
+    }
+
+    /// Extensions `(name, value)` sent by the server in its hello message.
+    pub fn server_extensions(&self) -> &[Extension] {
+        self.protocol.server_extensions()
+    }
+
+    /// Send a ping to the server and return pid of the ssh mux server
+    /// if it is still alive.
+    pub fn send_alive_check(&mut self) -> Result<NonZeroU32> {
+        let request_id = self.protocol.send_alive_check()?;
+
+        match self.wait_for_reply(request_id, &[])? {
+            Reply::Alive { pid } => Ok(pid),
+            reply => unreachable!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Opens a new session.
+    ///
+    /// See [`crate::Connection::open_new_session`] for details.
+    ///
+    /// * `fds` - must be in blocking mode
+    pub fn open_new_session(
+        mut self,
+        session: &Session<'_>,
+        fds: &[RawFd; 3],
+    ) -> Result<EstablishedSession> {
+        let request_id = self.protocol.send_new_session(session)?;
+
+        match self.wait_for_reply(request_id, fds)? {
+            Reply::SessionOpened { .. } => Ok(EstablishedSession { conn: self }),
+            reply => unreachable!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Same as [`Connection::open_new_session`], but takes any type
+    /// implementing [`AsFd`].
+    ///
+    /// The fds must be in blocking mode.
+    pub fn open_new_session_with_fds<I, O, E>(
+        self,
+        session: &Session<'_>,
+        stdin: I,
+        stdout: O,
+        stderr: E,
+    ) -> Result<EstablishedSession>
+    where
+        I: AsFd,
+        O: AsFd,
+        E: AsFd,
+    {
+        let fds = [
+            stdin.as_fd().as_raw_fd(),
+            stdout.as_fd().as_raw_fd(),
+            stderr.as_fd().as_raw_fd(),
+        ];
+
+        self.open_new_session(session, &fds)
+    }
+
+    /// Convenient function for opening a new session running subsystem
+    /// `name`, uses `open_new_session` underlying.
+    pub fn subsystem(
+        self,
+        name: &NonZeroByteSlice,
+        fds: &[RawFd; 3],
+    ) -> Result<EstablishedSession> {
+        self.open_new_session(&subsystem_session(name), fds)
+    }
+
+    /// Convenient function for opening a new sftp session, uses
+    /// `open_new_session` underlying.
+    pub fn sftp(self, fds: &[RawFd; 3]) -> Result<EstablishedSession> {
+        self.subsystem("sftp".try_into().unwrap(), fds)
+    }
+
+    /// Request for local/remote port forwarding.
+    pub fn request_port_forward(
+        &mut self,
+        forward_type: ForwardType,
+        listen_socket: &Socket<'_>,
+        connect_socket: &Socket<'_>,
+    ) -> Result<()> {
+        let request_id =
+            self.protocol
+                .send_port_forward(forward_type, listen_socket, connect_socket)?;
+
+        self.wait_for_ok(request_id)
+    }
+
+    /// Request for local/remote port forwarding closure.
+    pub fn close_port_forward(
+        &mut self,
+        forward_type: ForwardType,
+        listen_socket: &Socket<'_>,
+        connect_socket: &Socket<'_>,
+    ) -> Result<()> {
+        let request_id =
+            self.protocol
+                .send_close_port_forward(forward_type, listen_socket, connect_socket)?;
+
+        self.wait_for_ok(request_id)
+    }
+
+    /// Send all `forwards` back-to-back and then wait for all of their
+    /// responses.
+    ///
+    /// See [`crate::Connection::request_forwards`] for details.
+    pub fn request_forwards(&mut self, forwards: &[ForwardRequest<'_>]) -> Result<Vec<Result<()>>> {
+        let request_ids = self.protocol.send_forwards(forwards)?;
+        self.flush(&[])?;
+
+        let mut results = BatchResults::new(request_ids);
+        while !results.is_done() {
+            results.handle_event(self.next_event()?)?;
+        }
+
+        Ok(results.into_results())
+    }
+
+    /// **UNTESTED** Return remote port opened for dynamic forwarding.
+    pub fn request_dynamic_forward(&mut self, listen_socket: &Socket<'_>) -> Result<NonZeroU32> {
+        let request_id = self.protocol.send_dynamic_forward(listen_socket)?;
+
+        match self.wait_for_reply(request_id, &[])? {
+            Reply::RemotePort { port } => Ok(port),
+            reply => unreachable!("Unexpected reply {:?}", reply),
+        }
+    }
+
+    /// Request the master to stop accepting new multiplexing requests
+    /// and remove its listener socket.
+    pub fn request_stop_listening(&mut self) -> Result<()> {
+        blocking_io::request_stop_listening(&self.raw_conn, &mut self.protocol)
+    }
+
+    /// Request the master to terminate, closing all of its sessions.
+    ///
+    /// See [`crate::Connection::request_terminate`] for details.
+    pub fn request_terminate(mut self) -> Result<()> {
+        let request_id = self.protocol.send_terminate()?;
+
+        match self.wait_for_ok(request_id) {
+            Err(err) if is_closed_by_master(&err) => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Blocking counterpart of [`EstablishedSession`](crate::EstablishedSession).
+///
+/// NOTE that once `EstablishedSession` is dropped, any data written to
+/// `stdin` will not be send to the remote process and
+/// `stdout` and `stderr` would eof immediately.
+#[derive(Debug)]
+pub struct EstablishedSession {
+    conn: Connection,
+}
+impl EstablishedSession {
+    /// Wait for session status to change.
+    ///
+    /// If the server close the connection without sending anything,
+    /// this function would return `Ok(SessionEvent::Exited { exit_value: None })`.
+    pub fn wait_event(&mut self) -> Result<SessionEvent> {
+        match self.conn.next_event() {
+            Ok(Event::Session { event, .. }) => Ok(event),
+            Ok(event) => Err(Error::invalid_server_response(
+                &"TtyAllocFail or ExitMessage",
+                &event,
+            )),
+            Err(Error::IOError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                Ok(SessionEvent::Exited { exit_value: None })
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Wait for session status to change
+    ///
+    /// Return `Self` on error so that you can handle the error and restart
+    /// the operation.
+    pub fn wait(mut self) -> Result<SessionStatus, (Error, Self)> {
+        match self.wait_event() {
+            Ok(SessionEvent::Exited { exit_value }) => Ok(SessionStatus::Exited { exit_value }),
+            Ok(SessionEvent::TtyAllocFail) => Ok(SessionStatus::TtyAllocFail(self)),
+            Err(err) => Err((err, self)),
+        }
+    }
+}
+
+/// Blocking counterpart of [`SessionStatus`](crate::SessionStatus).
+#[derive(Debug)]
+pub enum SessionStatus {
+    /// Remote ssh server failed to allocate a tty, you can now return the tty
+    /// to cooked mode.
+    ///
+    /// This arm includes `EstablishedSession` so that you can call `wait` on it
+    /// again and retrieve the exit status and the underlying connection.
+    TtyAllocFail(EstablishedSession),
+
+    /// The process on the remote machine has exited with `exit_value`.
+    Exited { exit_value: Option<u32> },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "master")]
+    use crate::MasterBuilder;
+
+    use std::{
+        borrow::Cow,
+        env,
+        fs::File,
+        io::{self, Read},
+        os::unix::io::AsRawFd,
+    };
+
+    const PATH: &str = "/tmp/openssh-mux-client-test.socket";
+
+    #[cfg(feature = "master")]
+    const STUB_SSH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../testfiles/stub_ssh");
+
+    #[cfg(feature = "master")]
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_blocking_stub() {
+        let path = Path::new("/tmp/openssh-mux-client-blocking.socket");
+
+        let mut master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+
+        let mut conn =
+            Connection::connect_with_extensions(path, &[("client@example.com", "1")]).unwrap();
+        assert_matches!(
+            conn.server_extensions(),
+            [(name, value)] if &**name == b"stub@openssh-mux-client" && &**value == b"1"
+        );
+
+        let pid = conn.send_alive_check().unwrap();
+        assert_eq!(pid.get(), master.id().unwrap());
+
+        // The stub replies failure to requests other than alive check
+        // and stop listening.
+        let socket = Socket::TcpSocket {
+            port: 1234,
+            host: "127.0.0.1".into(),
+        };
+        assert_matches!(
+            conn.request_port_forward(ForwardType::Local, &socket, &socket),
+            Err(Error::RequestFailure(_))
+        );
+
+        let forward = ForwardRequest::Open {
+            forward_type: ForwardType::Local,
+            listen_socket: &socket,
+            connect_socket: &socket,
+        };
+        let results = conn.request_forwards(&[forward, forward]).unwrap();
+        assert_matches!(
+            results.as_slice(),
+            [Err(Error::RequestFailure(_)), Err(Error::RequestFailure(_))]
+        );
+
+        conn.request_stop_listening().unwrap();
+        assert!(!path.exists());
+
+        // The master exits once all connections are closed
+        drop(conn);
+        assert!(master.wait().await.unwrap().success());
+    }
+
+    #[test]
+    fn test_unordered_blocking_alive_check() {
+        let expected_pid: u32 = env::var("ControlMasterPID").unwrap().parse().unwrap();
+
+        let mut conn = Connection::connect(PATH).unwrap();
+        assert_eq!(conn.send_alive_check().unwrap().get(), expected_pid);
+    }
+
+    #[test]
+    fn test_unordered_blocking_open_new_session() {
+        let session = Session::builder()
+            .cmd(Cow::Borrowed("echo -n hello".try_into().unwrap()))
+            .build();
+
+        let (stdout_read, stdout_write) = rustix::pipe::pipe().unwrap();
+
+        let established_session = Connection::connect(PATH)
+            .unwrap()
+            .open_new_session(
+                &session,
+                &[
+                    io::stdin().as_raw_fd(),
+                    stdout_write.as_raw_fd(),
+                    io::stderr().as_raw_fd(),
+                ],
+            )
+            .unwrap();
+        drop(stdout_write);
+
+        let mut output = String::new();
+        File::from(stdout_read).read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello");
+
+        assert_matches!(
+            established_session.wait().unwrap(),
+            SessionStatus::Exited {
+                exit_value: Some(0)
+            }
+        );
+    }
+}
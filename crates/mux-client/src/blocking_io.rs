@@ -0,0 +1,135 @@
+#![forbid(unsafe_code)]
+
+//! Blocking I/O driving [`Protocol`] over [`UnixStream`].
+//!
+//! It is shared by `blocking::Connection` and the synchronous functions
+//! that are available without feature `blocking`.
+
+use crate::{
+    proto::{Event, Protocol, Reply, Transmit},
+    Error, ErrorExt, Result,
+};
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    os::unix::{io::RawFd, net::UnixStream},
+    path::Path,
+};
+
+use sendfd::SendWithFd;
+
+/// Connect to the control socket at `path` and exchange hello.
+pub(crate) fn connect<P: AsRef<Path>>(
+    path: P,
+    extensions: &[(&str, &str)],
+) -> Result<(UnixStream, Box<Protocol>)> {
+    let raw_conn = UnixStream::connect(path)?;
+    let mut protocol = Box::new(Protocol::with_extensions(extensions));
+
+    flush(&raw_conn, &mut protocol, &[])?;
+
+    match next_event(&raw_conn, &mut protocol)? {
+        Event::Hello => Ok((raw_conn, protocol)),
+        event => Err(Error::invalid_server_response(&"Hello message", &event)),
+    }
+}
+
+/// Send everything queued in `protocol`.
+///
+/// * `fds` - fds of the new session, if any.
+pub(crate) fn flush(
+    mut raw_conn: &UnixStream,
+    protocol: &mut Protocol,
+    fds: &[RawFd],
+) -> Result<()> {
+    while let Some(transmit) = protocol.poll_transmit() {
+        let res = match transmit {
+            Transmit::Bytes(bytes) => raw_conn.write(bytes),
+            Transmit::Fd(index) => SendWithFd::send_with_fd(raw_conn, &[0], &[fds[index]]),
+        };
+
+        let n = match res {
+            Ok(n) => n,
+            // Nothing is sent, retry the same transmit.
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+
+        if n == 0 {
+            return Err(io::Error::from(ErrorKind::WriteZero).into());
+        }
+
+        protocol.advance_transmit(n);
+    }
+
+    Ok(())
+}
+
+/// Read in the next event.
+pub(crate) fn next_event(mut raw_conn: &UnixStream, protocol: &mut Protocol) -> Result<Event> {
+    let mut buffer = [0_u8; 256];
+
+    loop {
+        if let Some(event) = protocol.poll_event()? {
+            break Ok(event);
+        }
+
+        let n = protocol.bytes_needed().min(buffer.len());
+        let n = match raw_conn.read(&mut buffer[..n]) {
+            Ok(n) => n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        if n == 0 {
+            break Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+
+        protocol.handle_input(&buffer[..n]);
+    }
+}
+
+/// Send the request queued in `protocol` with `request_id`
+/// and wait for its reply.
+pub(crate) fn wait_for_reply(
+    raw_conn: &UnixStream,
+    protocol: &mut Protocol,
+    request_id: u32,
+    fds: &[RawFd],
+) -> Result<Reply> {
+    flush(raw_conn, protocol, fds)?;
+
+    loop {
+        match next_event(raw_conn, protocol)? {
+            Event::Response {
+                request_id: response_id,
+                result,
+            } if response_id == request_id => break result,
+            Event::Response { .. } => (),
+            event => {
+                break Err(Error::invalid_server_response(
+                    &"Response to request",
+                    &event,
+                ))
+            }
+        }
+    }
+}
+
+pub(crate) fn wait_for_ok(
+    raw_conn: &UnixStream,
+    protocol: &mut Protocol,
+    request_id: u32,
+) -> Result<()> {
+    match wait_for_reply(raw_conn, protocol, request_id, &[])? {
+        Reply::Ok => Ok(()),
+        reply => unreachable!("Unexpected reply {:?}", reply),
+    }
+}
+
+/// Request the master to stop accepting new multiplexing requests
+/// and remove its listener socket.
+pub(crate) fn request_stop_listening(raw_conn: &UnixStream, protocol: &mut Protocol) -> Result<()> {
+    let request_id = protocol.send_stop_listening()?;
+
+    wait_for_ok(raw_conn, protocol, request_id)
+}
@@ -0,0 +1,462 @@
+#![forbid(unsafe_code)]
+
+//! A [`std::process::Command`]-like builder for running commands on the
+//! remote machine through the ssh multiplex master.
+
+use super::{
+    Connection, Error, EstablishedSession, NonZeroByteVec, RemoteChild, Result, Session,
+    SessionEvent,
+};
+
+use std::{
+    borrow::Cow,
+    ffi::{OsStr, OsString},
+    fs::OpenOptions,
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsFd, OwnedFd},
+    },
+};
+
+use tokio::{
+    io::{self as tokio_io, AsyncRead, AsyncReadExt},
+    net::unix::pipe,
+    try_join,
+};
+
+/// Describes what to do with a standard io stream of the remote process.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Stdio {
+    /// The stream is connected to the corresponding stream of
+    /// the current process.
+    Inherit,
+
+    /// A new pipe is created and the other end is returned in
+    /// [`RemoteChild`].
+    Piped,
+
+    /// The stream is connected to `/dev/null`.
+    Null,
+}
+
+/// Output of a finished remote process, returned by [`Command::output`].
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Output {
+    /// Exit value of the remote process, `None` if the server closed
+    /// the connection without sending it.
+    pub exit_value: Option<u32>,
+
+    /// Data the process wrote to stdout.
+    pub stdout: Vec<u8>,
+
+    /// Data the process wrote to stderr.
+    pub stderr: Vec<u8>,
+}
+
+/// Builder for a remote process.
+///
+/// The program, its arguments and the environment variables are quoted
+/// and joined into a single command line, which the remote sshd passes to
+/// the login shell of the remote user.
+#[derive(Clone, Debug)]
+pub struct Command {
+    program: OsString,
+    args: Vec<OsString>,
+    envs: Vec<(OsString, OsString)>,
+    tty: bool,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+}
+
+impl Command {
+    /// Create a new `Command` for running `program` on the remote machine.
+    pub fn new<S: AsRef<OsStr>>(program: S) -> Self {
+        Self {
+            program: program.as_ref().to_owned(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            tty: false,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+        }
+    }
+
+    /// Add an argument to pass to the program.
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    /// Add multiple arguments to pass to the program.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|arg| arg.as_ref().to_owned()));
+        self
+    }
+
+    /// Set an environment variable for the program.
+    ///
+    /// `key` must be a valid shell variable name, otherwise
+    /// [`Error::InvalidCommand`] is returned when the command is run.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Self
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.envs
+            .push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Request a tty to be allocated for the remote process,
+    /// defaults to `false`.
+    pub fn tty(&mut self, tty: bool) -> &mut Self {
+        self.tty = tty;
+        self
+    }
+
+    /// Configuration for stdin of the remote process.
+    ///
+    /// Defaults to [`Stdio::Inherit`] for [`Command::spawn`] and
+    /// [`Command::status`] and [`Stdio::Null`] for [`Command::output`].
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut Self {
+        self.stdin = Some(cfg);
+        self
+    }
+
+    /// Configuration for stdout of the remote process.
+    ///
+    /// Defaults to [`Stdio::Inherit`] for [`Command::spawn`] and
+    /// [`Command::status`] and [`Stdio::Piped`] for [`Command::output`].
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut Self {
+        self.stdout = Some(cfg);
+        self
+    }
+
+    /// Configuration for stderr of the remote process.
+    ///
+    /// Defaults to [`Stdio::Inherit`] for [`Command::spawn`] and
+    /// [`Command::status`] and [`Stdio::Piped`] for [`Command::output`].
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut Self {
+        self.stderr = Some(cfg);
+        self
+    }
+
+    /// Return the command line to be run by the remote shell.
+    fn cmd(&self) -> Result<NonZeroByteVec> {
+        let mut cmd = Vec::new();
+
+        for (key, val) in &self.envs {
+            let key = key.as_bytes();
+
+            if !is_valid_env_key(key) {
+                return Err(Error::InvalidCommand(
+                    format!(
+                        "invalid environment variable name {:?}",
+                        OsStr::from_bytes(key)
+                    )
+                    .into(),
+                ));
+            }
+
+            cmd.extend_from_slice(key);
+            cmd.push(b'=');
+            quote(val.as_bytes(), &mut cmd);
+            cmd.push(b' ');
+        }
+
+        quote(self.program.as_bytes(), &mut cmd);
+
+        for arg in &self.args {
+            cmd.push(b' ');
+            quote(arg.as_bytes(), &mut cmd);
+        }
+
+        NonZeroByteVec::new(cmd)
+            .ok_or_else(|| Error::InvalidCommand("command contains null byte".into()))
+    }
+
+    async fn spawn_impl(
+        &self,
+        conn: Connection,
+        default: [Stdio; 3],
+    ) -> Result<(EstablishedSession, RemoteChild)> {
+        let cmd = self.cmd()?;
+        let session = Session::builder()
+            .tty(self.tty)
+            .cmd(Cow::Borrowed(&*cmd))
+            .build();
+
+        let (stdin, child_stdin) = match self.stdin.unwrap_or(default[0]) {
+            Stdio::Piped => {
+                let (sender, receiver) = pipe::pipe()?;
+                (receiver.into_blocking_fd()?, Some(sender))
+            }
+            cfg => (open_stdio(cfg, io::stdin(), false)?, None),
+        };
+        let (stdout, child_stdout) =
+            create_output(self.stdout.unwrap_or(default[1]), io::stdout())?;
+        let (stderr, child_stderr) =
+            create_output(self.stderr.unwrap_or(default[2]), io::stderr())?;
+
+        let established_session = conn
+            .open_new_session_with_fds(&session, stdin, stdout, stderr)
+            .await?;
+
+        let remote_child = RemoteChild {
+            stdin: child_stdin,
+            stdout: child_stdout,
+            stderr: child_stderr,
+        };
+
+        Ok((established_session, remote_child))
+    }
+
+    /// Run the command on the remote machine, return the session
+    /// and the handles to the piped stdio.
+    ///
+    /// By default, stdin, stdout and stderr are inherited from the
+    /// current process.
+    pub async fn spawn(&self, conn: Connection) -> Result<(EstablishedSession, RemoteChild)> {
+        self.spawn_impl(conn, [Stdio::Inherit; 3]).await
+    }
+
+    /// Run the command on the remote machine and wait for it to exit.
+    ///
+    /// By default, stdin, stdout and stderr are inherited from the
+    /// current process.
+    ///
+    /// Like [`std::process::Command::status`], piped stdin is closed
+    /// before waiting. Piped stdout and stderr are read and discarded,
+    /// so that the remote process cannot block on a full pipe.
+    ///
+    /// Return `None` if the server closed the connection without sending
+    /// the exit value.
+    pub async fn status(&self, conn: Connection) -> Result<Option<u32>> {
+        let (established_session, remote_child) =
+            self.spawn_impl(conn, [Stdio::Inherit; 3]).await?;
+
+        let RemoteChild {
+            stdin,
+            stdout,
+            stderr,
+        } = remote_child;
+        drop(stdin);
+
+        let (_, _, exit_value) =
+            try_join!(discard(stdout), discard(stderr), wait(established_session))?;
+
+        Ok(exit_value)
+    }
+
+    /// Run the command on the remote machine, wait for it to exit and
+    /// collect all of its output.
+    ///
+    /// By default, stdout and stderr are captured and stdin is
+    /// connected to `/dev/null`.
+    pub async fn output(&self, conn: Connection) -> Result<Output> {
+        let (established_session, remote_child) = self
+            .spawn_impl(conn, [Stdio::Null, Stdio::Piped, Stdio::Piped])
+            .await?;
+
+        let RemoteChild {
+            stdin,
+            stdout,
+            stderr,
+        } = remote_child;
+        drop(stdin);
+
+        let (stdout, stderr, exit_value) = try_join!(
+            read_to_end(stdout),
+            read_to_end(stderr),
+            wait(established_session)
+        )?;
+
+        Ok(Output {
+            exit_value,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Return the fd to pass to the ssh multiplex master for
+/// [`Stdio::Inherit`] or [`Stdio::Null`].
+fn open_stdio(cfg: Stdio, inherited: impl AsFd, write: bool) -> io::Result<OwnedFd> {
+    match cfg {
+        Stdio::Inherit => inherited.as_fd().try_clone_to_owned(),
+        Stdio::Null => Ok(OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .open("/dev/null")?
+            .into()),
+        Stdio::Piped => unreachable!("piped stdio is handled by the caller"),
+    }
+}
+
+fn create_output(
+    cfg: Stdio,
+    inherited: impl AsFd,
+) -> io::Result<(OwnedFd, Option<pipe::Receiver>)> {
+    match cfg {
+        Stdio::Piped => {
+            let (sender, receiver) = pipe::pipe()?;
+            Ok((sender.into_blocking_fd()?, Some(receiver)))
+        }
+        cfg => Ok((open_stdio(cfg, inherited, true)?, None)),
+    }
+}
+
+async fn read_to_end<R: AsyncRead + Unpin>(reader: Option<R>) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    if let Some(mut reader) = reader {
+        reader.read_to_end(&mut buffer).await?;
+    }
+    Ok(buffer)
+}
+
+async fn discard<R: AsyncRead + Unpin>(reader: Option<R>) -> Result<()> {
+    if let Some(mut reader) = reader {
+        tokio_io::copy(&mut reader, &mut tokio_io::sink()).await?;
+    }
+    Ok(())
+}
+
+async fn wait(mut established_session: EstablishedSession) -> Result<Option<u32>> {
+    loop {
+        // The tty of the remote process is not managed here, so there is
+        // nothing to restore on `TtyAllocFail`.
+        if let SessionEvent::Exited { exit_value } = established_session.wait_event().await? {
+            break Ok(exit_value);
+        }
+    }
+}
+
+fn is_valid_env_key(key: &[u8]) -> bool {
+    match key.split_first() {
+        Some((first, rest)) => {
+            (first.is_ascii_alphabetic() || *first == b'_')
+                && rest.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'_')
+        }
+        None => false,
+    }
+}
+
+/// Quote `arg` for POSIX shell and append it to `out`.
+///
+/// `arg` is left as is if it only consists of characters that have
+/// no special meaning, otherwise it is put in single quotes with
+/// every `'` replaced by `'\''`.
+///
+/// `=` is not considered safe, since the shell would take an unquoted
+/// program like `A=b` as a variable assignment.
+fn quote(arg: &[u8], out: &mut Vec<u8>) {
+    let is_safe = |c: &u8| c.is_ascii_alphanumeric() || b"_-./:,+@%".contains(c);
+
+    if !arg.is_empty() && arg.iter().all(is_safe) {
+        out.extend_from_slice(arg);
+        return;
+    }
+
+    out.push(b'\'');
+    for c in arg {
+        if *c == b'\'' {
+            out.extend_from_slice(b"'\\''");
+        } else {
+            out.push(*c);
+        }
+    }
+    out.push(b'\'');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmd() {
+        let cmd = Command::new("printf")
+            .arg("%s\n")
+            .args(["it's", "", "a b", "/tmp/x.txt"])
+            .env("LC_ALL", "C")
+            .env("GREETING", "hello world")
+            .cmd()
+            .unwrap();
+
+        assert_eq!(
+            cmd.into_inner(),
+            b"LC_ALL=C GREETING='hello world' printf '%s\n' 'it'\\''s' '' 'a b' /tmp/x.txt"
+        );
+    }
+
+    #[test]
+    fn test_program_with_equal_sign() {
+        let cmd = Command::new("A=b").arg("c=d").cmd().unwrap();
+
+        assert_eq!(cmd.into_inner(), b"'A=b' 'c=d'");
+    }
+
+    #[test]
+    fn test_invalid_cmd() {
+        assert_matches!(
+            Command::new("true").env("A B", "1").cmd(),
+            Err(Error::InvalidCommand(_))
+        );
+        assert_matches!(
+            Command::new("echo").arg("\0").cmd(),
+            Err(Error::InvalidCommand(_))
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unordered_command_output() {
+        let conn = Connection::connect("/tmp/openssh-mux-client-test.socket")
+            .await
+            .unwrap();
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg("printf '%s' \"$GREETING\"; echo \"it's\" >&2; exit 3")
+            .env("GREETING", "hello 'world'")
+            .output(conn)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            output,
+            Output {
+                exit_value: Some(3),
+                stdout: b"hello 'world'".to_vec(),
+                stderr: b"it's\n".to_vec(),
+            }
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unordered_command_status_piped() {
+        let conn = Connection::connect("/tmp/openssh-mux-client-test.socket")
+            .await
+            .unwrap();
+
+        // `cat` only exits once stdin is closed, and then it writes more
+        // than a pipe can hold.
+        let exit_value = Command::new("sh")
+            .arg("-c")
+            .arg("cat && head -c 1048576 /dev/zero && head -c 1048576 /dev/zero >&2")
+            .stdin(Stdio::Piped)
+            .stdout(Stdio::Piped)
+            .stderr(Stdio::Piped)
+            .status(conn)
+            .await
+            .unwrap();
+
+        assert_eq!(exit_value, Some(0));
+    }
+}
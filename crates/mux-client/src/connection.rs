@@ -1,28 +1,26 @@
 #![forbid(unsafe_code)]
 
 use crate::{
-    constants,
-    request::{Fwd, Request, SessionZeroCopy},
-    shutdown_mux_master::shutdown_mux_master_from,
-    utils::{serialize_u32, SliceExt},
-    Error, ErrorExt, EstablishedSession, Response, Result, Session, Socket,
+    blocking_io,
+    proto::{BatchResults, Event, Protocol, Reply, Transmit},
+    Error, ErrorExt, EstablishedSession, Extension, NonZeroByteSlice, RemoteChild, Result, Session,
+    Socket,
 };
 
 use std::{
     borrow::Cow,
     convert::TryInto,
     io,
-    io::IoSlice,
-    num::{NonZeroU32, Wrapping},
-    os::unix::io::RawFd,
+    num::NonZeroU32,
+    os::unix::io::{AsFd, AsRawFd, RawFd},
     path::Path,
 };
 
 use sendfd::SendWithFd;
-use serde::{de::DeserializeOwned, Serialize};
-use ssh_format::{from_bytes, Serializer};
-use tokio::net::UnixStream;
-use tokio_io_utility::{read_to_vec_rng, write_vectored_all};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{unix::pipe, UnixStream},
+};
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub enum ForwardType {
@@ -30,75 +28,141 @@ pub enum ForwardType {
     Remote,
 }
 
+/// A request in the batch passed to [`Connection::request_forwards`].
+#[derive(Copy, Clone, Debug)]
+pub enum ForwardRequest<'a> {
+    /// Same as [`Connection::request_port_forward`].
+    Open {
+        forward_type: ForwardType,
+        listen_socket: &'a Socket<'a>,
+        connect_socket: &'a Socket<'a>,
+    },
+
+    /// Same as [`Connection::close_port_forward`].
+    Close {
+        forward_type: ForwardType,
+        listen_socket: &'a Socket<'a>,
+        connect_socket: &'a Socket<'a>,
+    },
+}
+
 /// # Cancel safety
 ///
-/// All methods of this struct is not cancellation safe.
+/// [`Connection::send_alive_check`], [`Connection::request_port_forward`],
+/// [`Connection::close_port_forward`], [`Connection::request_dynamic_forward`]
+/// and [`Connection::request_stop_listening`] are cancellation safe.
+///
+/// If one of them is cancelled, the partially written request and the
+/// partially read response are kept in `Connection`:
+///  - Calling the same method with the same arguments again resumes the
+///    cancelled request instead of sending a new one.
+///  - Calling any other method first completes the cancelled request and
+///    discards its response.
+///
+/// Methods that consume `self` are trivially cancellation safe since the
+/// connection is dropped on cancellation.
+///
+/// # Unknown messages
+///
+/// If the server sends a message of type unknown to this crate, the method
+/// fails with [`Error::UnsupportedResponse`]. The message is consumed,
+/// so the `Connection` stays usable and the request can be retried
+/// as described above if the message did not respond to it.
 #[derive(Debug)]
 pub struct Connection {
     raw_conn: UnixStream,
-    serializer: Serializer,
-    read_buffer: Vec<u8>,
-    request_id: Wrapping<u32>,
+    /// Boxed to keep [`EstablishedSession`] and the `Err` returned by
+    /// [`EstablishedSession::wait`] small.
+    protocol: Box<Protocol>,
+    /// Set once reading from or writing to the control socket fails.
+    broken: bool,
 }
 impl Connection {
-    fn reset_serializer(&mut self) {
-        self.serializer.reset_counter();
-        self.serializer.output.clear();
+    /// Send everything queued in `self.protocol`.
+    ///
+    /// * `fds` - fds of the new session, if any.
+    async fn flush(&mut self, fds: &[RawFd]) -> Result<()> {
+        let res = self.flush_impl(fds).await;
+        self.broken |= res.is_err();
+        res
     }
 
-    async fn write(&mut self, value: &Request) -> Result<()> {
-        self.reset_serializer();
-        value.serialize(&mut self.serializer)?;
+    async fn flush_impl(&mut self, fds: &[RawFd]) -> Result<()> {
+        while let Some(transmit) = self.protocol.poll_transmit() {
+            let n = match transmit {
+                Transmit::Bytes(bytes) => self.raw_conn.write(bytes).await?,
+                Transmit::Fd(index) => {
+                    let fd = fds[index];
+                    self.send_with_fds(&[fd]).await?;
+                    1
+                }
+            };
 
-        let header = self.serializer.create_header(0)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero).into());
+            }
 
-        write_vectored_all(
-            &mut self.raw_conn,
-            &mut [IoSlice::new(&header), IoSlice::new(&self.serializer.output)],
-        )
-        .await?;
+            self.protocol.advance_transmit(n);
+        }
 
         Ok(())
     }
 
-    fn deserialize<T: DeserializeOwned>(read_buffer: &[u8]) -> Result<T> {
-        // Ignore any trailing bytes to be forward compatible
-        Ok(from_bytes(read_buffer)?.0)
-    }
-
-    pub(crate) async fn read_response(&mut self) -> Result<Response> {
-        let buffer = &mut self.read_buffer;
-
-        if buffer.len() < 4 {
-            let n = 4 - buffer.len();
-            read_to_vec_rng(&mut self.raw_conn, buffer, n..).await?;
-        }
-
-        // Read in the header
-        let packet_len: u32 = Self::deserialize(&buffer[..4])?;
+    /// Read in the next event.
+    ///
+    /// This function is cancel safe since partially read data is kept
+    /// in `self.protocol`.
+    pub(crate) async fn next_event(&mut self) -> Result<Event> {
+        let mut buffer = [0_u8; 256];
 
-        let packet_len: usize = packet_len.try_into().unwrap();
+        loop {
+            if let Some(event) = self.protocol.poll_event()? {
+                break Ok(event);
+            }
 
-        // The first 4 bytes are not counted as the packet body
-        let buffer_len = buffer.len() - 4;
+            let n = self.protocol.bytes_needed().min(buffer.len());
+            let n = match self.raw_conn.read(&mut buffer[..n]).await {
+                Ok(0) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                res => res,
+            };
+            let n = match n {
+                Ok(n) => n,
+                Err(err) => {
+                    self.broken = true;
+                    break Err(err.into());
+                }
+            };
 
-        if buffer_len < packet_len {
-            // Read in rest of the packet
-            let n = packet_len - buffer_len;
-            read_to_vec_rng(&mut self.raw_conn, buffer, n..).await?;
+            self.protocol.handle_input(&buffer[..n]);
         }
+    }
 
-        // Deserialize the response
-        let response = Self::deserialize(&buffer[4..(4 + packet_len)])?;
-
-        // Remove the packet from buffer
-        buffer.drain(..(4 + packet_len));
+    /// Send the request queued in `self.protocol` with `request_id`
+    /// and wait for its reply.
+    ///
+    /// Responses to requests cancelled before are discarded.
+    async fn wait_for_reply(&mut self, request_id: u32, fds: &[RawFd]) -> Result<Reply> {
+        self.flush(fds).await?;
 
-        Ok(response)
+        loop {
+            match self.next_event().await? {
+                Event::Response {
+                    request_id: response_id,
+                    result,
+                } if response_id == request_id => break result,
+                Event::Response { .. } => (),
+                event => {
+                    break Err(Error::invalid_server_response(
+                        &"Response to request",
+                        &event,
+                    ))
+                }
+            }
+        }
     }
 
     /// Send fds with "\0"
-    async fn send_with_fds(&self, fds: &[RawFd]) -> Result<()> {
+    async fn send_with_fds(&self, fds: &[RawFd]) -> Result<usize> {
         let byte = &[0];
 
         loop {
@@ -106,14 +170,7 @@ impl Connection {
 
             // send_with_fd calls `UnixStream::try_io`
             match SendWithFd::send_with_fd(&self.raw_conn, byte, fds) {
-                Ok(n) => {
-                    if n == 1 {
-                        break Ok(());
-                    } else {
-                        debug_assert_eq!(n, 0);
-                        break Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
-                    }
-                }
+                Ok(n) => break Ok(n),
                 Err(e) => {
                     if e.kind() != io::ErrorKind::WouldBlock {
                         break Err(e.into());
@@ -123,163 +180,56 @@ impl Connection {
         }
     }
 
-    fn get_request_id(&mut self) -> u32 {
-        let request_id = self.request_id.0;
-        self.request_id += Wrapping(1);
-
-        request_id
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::connect_with_extensions(path, &[]).await
     }
 
-    fn check_response_id(request_id: u32, response_id: u32) -> Result<()> {
-        if request_id != response_id {
-            Err(Error::UnmatchedRequestId)
-        } else {
-            Ok(())
-        }
-    }
+    /// Same as [`Connection::connect`], but sends `extensions` as
+    /// `(name, value)` pairs in the hello message.
+    ///
+    /// The server ignores extensions it does not recognise.
+    pub async fn connect_with_extensions<P: AsRef<Path>>(
+        path: P,
+        extensions: &[(&str, &str)],
+    ) -> Result<Self> {
+        let mut conn = Self {
+            raw_conn: UnixStream::connect(path).await?,
+            protocol: Box::new(Protocol::with_extensions(extensions)),
+            broken: false,
+        };
+        // Required by the cancel safety documented on `Connection`.
+        conn.protocol.set_resume_identical(true);
 
-    async fn exchange_hello(mut self) -> Result<Self> {
-        self.write(&Request::Hello {
-            version: constants::SSHMUX_VER,
-        })
-        .await?;
+        conn.flush(&[]).await?;
 
-        let response = self.read_response().await?;
-        if let Response::Hello { version } = response {
-            if version != constants::SSHMUX_VER {
-                Err(Error::UnsupportedMuxProtocol)
-            } else {
-                Ok(self)
-            }
-        } else {
-            Err(Error::invalid_server_response(&"Hello message", &response))
+        match conn.next_event().await? {
+            Event::Hello => Ok(conn),
+            event => Err(Error::invalid_server_response(&"Hello message", &event)),
         }
     }
 
-    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
-        Self {
-            raw_conn: UnixStream::connect(path).await?,
-            // All request packets are at least 12 bytes large,
-            // and variant [`Request::NewSession`] takes 36 bytes to
-            // serialize.
-            serializer: Serializer::new(Vec::with_capacity(36)),
-            // All reponse packets are at least 16 bytes large.
-            read_buffer: Vec::with_capacity(32),
-            request_id: Wrapping(0),
-        }
-        .exchange_hello()
-        .await
+    /// Return `true` if the connection is neither broken nor in the middle
+    /// of a request, so that it can be reused.
+    #[cfg(feature = "pool")]
+    pub(crate) fn is_reusable(&self) -> bool {
+        !self.broken && self.protocol.is_idle()
+    }
+
+    /// Extensions `(name, value)` sent by the server in its hello message,
+    /// which can be used to detect its capabilities.
+    pub fn server_extensions(&self) -> &[Extension] {
+        self.protocol.server_extensions()
     }
 
     /// Send a ping to the server and return pid of the ssh mux server
     /// if it is still alive.
     pub async fn send_alive_check(&mut self) -> Result<NonZeroU32> {
-        let request_id = self.get_request_id();
-
-        self.write(&Request::AliveCheck { request_id }).await?;
-
-        let response = self.read_response().await?;
-        if let Response::Alive {
-            response_id,
-            server_pid,
-        } = response
-        {
-            Self::check_response_id(request_id, response_id)?;
-            NonZeroU32::new(server_pid).ok_or(Error::InvalidPid)
-        } else {
-            Err(Error::invalid_server_response(
-                &"Response::Alive",
-                &response,
-            ))
-        }
-    }
-
-    /// Return session_id
-    async fn open_new_session_impl(
-        &mut self,
-        session: &Session<'_>,
-        fds: &[RawFd; 3],
-    ) -> Result<u32> {
-        use Response::*;
-
-        let request_id = self.get_request_id();
-
-        // Prepare to serialize
-        let term = session.term.as_ref().into_inner();
-        let cmd = session.cmd.as_ref().into_inner();
-
-        let term_len: u32 = term.get_len_as_u32()?;
-        let cmd_len: u32 = cmd.get_len_as_u32()?;
-
-        let request = Request::NewSession {
-            request_id,
-            session: SessionZeroCopy {
-                tty: session.tty,
-                x11_forwarding: session.x11_forwarding,
-                agent: session.agent,
-                subsystem: session.subsystem,
-                escape_ch: session.escape_ch,
-            },
-        };
+        let request_id = self.protocol.send_alive_check()?;
 
-        // Serialize
-        self.reset_serializer();
-
-        request.serialize(&mut self.serializer)?;
-        let serialized_header = self.serializer.create_header(
-            /* len of term */ 4 + term_len + /* len of cmd */ 4 + cmd_len,
-        )?;
-
-        let serialized_cmd_len = serialize_u32(cmd_len);
-        let serialized_term_len = serialize_u32(term_len);
-
-        // Write them to self.raw_conn
-        let mut io_slices = [
-            IoSlice::new(&serialized_header),
-            IoSlice::new(&self.serializer.output),
-            IoSlice::new(&serialized_term_len),
-            IoSlice::new(term),
-            IoSlice::new(&serialized_cmd_len),
-            IoSlice::new(cmd),
-        ];
-
-        write_vectored_all(&mut self.raw_conn, &mut io_slices).await?;
-
-        for fd in fds {
-            self.send_with_fds(&[*fd]).await?;
+        match self.wait_for_reply(request_id, &[]).await? {
+            Reply::Alive { pid } => Ok(pid),
+            reply => unreachable!("Unexpected reply {:?}", reply),
         }
-
-        let session_id = match self.read_response().await? {
-            SessionOpened {
-                response_id,
-                session_id,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                session_id
-            }
-            PermissionDenied {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                return Err(Error::PermissionDenied(reason));
-            }
-            Failure {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                return Err(Error::RequestFailure(reason));
-            }
-            response => {
-                return Err(Error::invalid_server_response(
-                    &"SessionOpened, PermissionDenied or Failure",
-                    &response,
-                ))
-            }
-        };
-
-        Result::Ok(session_id)
     }
 
     /// Opens a new session.
@@ -293,139 +243,129 @@ impl Connection {
     /// without sending an exit message.
     ///
     /// * `fds` - must be in blocking mode
+    ///
+    /// See also [`Connection::open_new_session_with_fds`] and
+    /// [`Connection::open_new_session_piped`].
     pub async fn open_new_session(
         mut self,
         session: &Session<'_>,
         fds: &[RawFd; 3],
     ) -> Result<EstablishedSession> {
-        let session_id = self.open_new_session_impl(session, fds).await?;
-
-        // EstablishedSession does not send any request
-        // It merely wait for response.
-        self.serializer.output = Vec::new();
+        let request_id = self.protocol.send_new_session(session)?;
 
-        Ok(EstablishedSession {
-            conn: self,
-            session_id,
-        })
+        match self.wait_for_reply(request_id, fds).await? {
+            Reply::SessionOpened { .. } => Ok(EstablishedSession { conn: self }),
+            reply => unreachable!("Unexpected reply {:?}", reply),
+        }
     }
 
-    /// Convenient function for opening a new sftp session, uses
-    /// `open_new_session` underlying.
-    pub async fn sftp(self, fds: &[RawFd; 3]) -> Result<EstablishedSession> {
-        let session = Session::builder()
-            .subsystem(true)
-            .term(Cow::Borrowed("".try_into().unwrap()))
-            .cmd(Cow::Borrowed("sftp".try_into().unwrap()))
-            .build();
+    /// Same as [`Connection::open_new_session`], but takes any type
+    /// implementing [`AsFd`], including [`OwnedFd`](std::os::unix::io::OwnedFd)
+    /// and references to files, pipes or sockets.
+    ///
+    /// The fds are sent to the ssh mux server, which duplicates them,
+    /// so owned fds can be dropped once this function returns.
+    ///
+    /// The fds must be in blocking mode.
+    pub async fn open_new_session_with_fds<I, O, E>(
+        self,
+        session: &Session<'_>,
+        stdin: I,
+        stdout: O,
+        stderr: E,
+    ) -> Result<EstablishedSession>
+    where
+        I: AsFd,
+        O: AsFd,
+        E: AsFd,
+    {
+        let fds = [
+            stdin.as_fd().as_raw_fd(),
+            stdout.as_fd().as_raw_fd(),
+            stderr.as_fd().as_raw_fd(),
+        ];
 
-        self.open_new_session(&session, fds).await
+        self.open_new_session(session, &fds).await
     }
 
-    async fn send_fwd_request(&mut self, request_id: u32, fwd: &Fwd<'_>) -> Result<()> {
-        let (fwd_mode, listen_socket, connect_socket) = fwd.as_serializable();
-        let (listen_addr, listen_port) = listen_socket.as_serializable();
-        let (connect_addr, connect_port) = connect_socket.as_ref().as_serializable();
-
-        let serialized_listen_port = serialize_u32(listen_port);
-        let serialized_connect_port = serialize_u32(connect_port);
-
-        let listen_addr_len: u32 = listen_addr.get_len_as_u32()?;
-        let connect_addr_len: u32 = connect_addr.get_len_as_u32()?;
+    /// Opens a new session with stdin, stdout and stderr connected to
+    /// newly created pipes.
+    ///
+    /// The other ends of the pipes are returned in [`RemoteChild`] as async
+    /// handles, so that you don't need to create and manage the fds yourself.
+    pub async fn open_new_session_piped(
+        self,
+        session: &Session<'_>,
+    ) -> Result<(EstablishedSession, RemoteChild)> {
+        let (stdin, stdin_read) = pipe::pipe()?;
+        let (stdout_write, stdout) = pipe::pipe()?;
+        let (stderr_write, stderr) = pipe::pipe()?;
+
+        let established_session = self
+            .open_new_session_with_fds(
+                session,
+                stdin_read.into_blocking_fd()?,
+                stdout_write.into_blocking_fd()?,
+                stderr_write.into_blocking_fd()?,
+            )
+            .await?;
 
-        let request = Request::OpenFwd {
-            request_id,
-            fwd_mode,
+        let remote_child = RemoteChild {
+            stdin: Some(stdin),
+            stdout: Some(stdout),
+            stderr: Some(stderr),
         };
 
-        // Serialize
-        self.reset_serializer();
-
-        request.serialize(&mut self.serializer)?;
-        let serialized_header = self.serializer.create_header(
-            // len
-            4 +
-            listen_addr_len +
-            // port
-            4 +
-            // len
-            4 +
-            connect_addr_len
-            // port
-            + 4,
-        )?;
-
-        let serialized_listen_addr_len = serialize_u32(listen_addr_len);
-        let serialized_connect_addr_len = serialize_u32(connect_addr_len);
-
-        // Write them to self.raw_conn
-        let mut io_slices = [
-            IoSlice::new(&serialized_header),
-            IoSlice::new(&self.serializer.output),
-            IoSlice::new(&serialized_listen_addr_len),
-            IoSlice::new(listen_addr.into_inner()),
-            IoSlice::new(&serialized_listen_port),
-            IoSlice::new(&serialized_connect_addr_len),
-            IoSlice::new(connect_addr.into_inner()),
-            IoSlice::new(&serialized_connect_port),
-        ];
-
-        write_vectored_all(&mut self.raw_conn, &mut io_slices).await?;
-
-        Ok(())
+        Ok((established_session, remote_child))
     }
 
-    async fn send_close_fwd_request(&mut self, request_id: u32, fwd: &Fwd<'_>) -> Result<()> {
-        let (fwd_mode, listen_socket, connect_socket) = fwd.as_serializable();
-        let (listen_addr, listen_port) = listen_socket.as_serializable();
-        let (connect_addr, connect_port) = connect_socket.as_ref().as_serializable();
-
-        let serialized_listen_port = serialize_u32(listen_port);
-        let serialized_connect_port = serialize_u32(connect_port);
-
-        let listen_addr_len: u32 = listen_addr.get_len_as_u32()?;
-        let connect_addr_len: u32 = connect_addr.get_len_as_u32()?;
+    /// Convenient function for opening a new session running subsystem
+    /// `name`, e.g. `sftp` or `netconf`, uses `open_new_session` underlying.
+    pub async fn subsystem(
+        self,
+        name: &NonZeroByteSlice,
+        fds: &[RawFd; 3],
+    ) -> Result<EstablishedSession> {
+        self.open_new_session(&subsystem_session(name), fds).await
+    }
 
-        let request = Request::CloseFwd {
-            request_id,
-            fwd_mode,
-        };
+    /// Same as [`Connection::subsystem`], but connects stdin and stdout of
+    /// the subsystem to newly created pipes and stderr to the stderr
+    /// of the current process.
+    ///
+    /// Return the writer to the stdin of the subsystem and the reader of
+    /// its stdout, ready to be handed to a client of the protocol spoken
+    /// by the subsystem.
+    pub async fn subsystem_piped(
+        self,
+        name: &NonZeroByteSlice,
+    ) -> Result<(EstablishedSession, pipe::Sender, pipe::Receiver)> {
+        let (stdin, stdin_read) = pipe::pipe()?;
+        let (stdout_write, stdout) = pipe::pipe()?;
+
+        let established_session = self
+            .open_new_session_with_fds(
+                &subsystem_session(name),
+                stdin_read.into_blocking_fd()?,
+                stdout_write.into_blocking_fd()?,
+                io::stderr(),
+            )
+            .await?;
 
-        // Serialize
-        self.reset_serializer();
-
-        request.serialize(&mut self.serializer)?;
-        let serialized_header = self.serializer.create_header(
-            // len
-            4 +
-            listen_addr_len +
-            // port
-            4 +
-            // len
-            4 +
-            connect_addr_len
-            // port
-            + 4,
-        )?;
-
-        let serialized_listen_addr_len = serialize_u32(listen_addr_len);
-        let serialized_connect_addr_len = serialize_u32(connect_addr_len);
-
-        // Write them to self.raw_conn
-        let mut io_slices = [
-            IoSlice::new(&serialized_header),
-            IoSlice::new(&self.serializer.output),
-            IoSlice::new(&serialized_listen_addr_len),
-            IoSlice::new(listen_addr.into_inner()),
-            IoSlice::new(&serialized_listen_port),
-            IoSlice::new(&serialized_connect_addr_len),
-            IoSlice::new(connect_addr.into_inner()),
-            IoSlice::new(&serialized_connect_port),
-        ];
+        Ok((established_session, stdin, stdout))
+    }
 
-        write_vectored_all(&mut self.raw_conn, &mut io_slices).await?;
+    /// Convenient function for opening a new sftp session, uses
+    /// `open_new_session` underlying.
+    pub async fn sftp(self, fds: &[RawFd; 3]) -> Result<EstablishedSession> {
+        self.subsystem("sftp".try_into().unwrap(), fds).await
+    }
 
-        Ok(())
+    async fn wait_for_ok(&mut self, request_id: u32) -> Result<()> {
+        match self.wait_for_reply(request_id, &[]).await? {
+            Reply::Ok => Ok(()),
+            reply => unreachable!("Unexpected reply {:?}", reply),
+        }
     }
 
     /// Request for local/remote port forwarding.
@@ -435,44 +375,11 @@ impl Connection {
         listen_socket: &Socket<'_>,
         connect_socket: &Socket<'_>,
     ) -> Result<()> {
-        use ForwardType::*;
-        use Response::*;
-
-        let fwd = match forward_type {
-            Local => Fwd::Local {
-                listen_socket,
-                connect_socket,
-            },
-            Remote => Fwd::Remote {
-                listen_socket,
-                connect_socket,
-            },
-        };
+        let request_id =
+            self.protocol
+                .send_port_forward(forward_type, listen_socket, connect_socket)?;
 
-        let request_id = self.get_request_id();
-        self.send_fwd_request(request_id, &fwd).await?;
-
-        match self.read_response().await? {
-            Ok { response_id } => Self::check_response_id(request_id, response_id),
-            PermissionDenied {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::PermissionDenied(reason))
-            }
-            Failure {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::RequestFailure(reason))
-            }
-            response => Err(Error::invalid_server_response(
-                &"Ok, PermissionDenied or Failure",
-                &response,
-            )),
-        }
+        self.wait_for_ok(request_id).await
     }
 
     /// Request for local/remote port forwarding closure.
@@ -482,44 +389,38 @@ impl Connection {
         listen_socket: &Socket<'_>,
         connect_socket: &Socket<'_>,
     ) -> Result<()> {
-        use ForwardType::*;
-        use Response::*;
-
-        let fwd = match forward_type {
-            Local => Fwd::Local {
-                listen_socket,
-                connect_socket,
-            },
-            Remote => Fwd::Remote {
-                listen_socket,
-                connect_socket,
-            },
-        };
+        let request_id =
+            self.protocol
+                .send_close_port_forward(forward_type, listen_socket, connect_socket)?;
 
-        let request_id = self.get_request_id();
-        self.send_close_fwd_request(request_id, &fwd).await?;
-
-        match self.read_response().await? {
-            Ok { response_id } => Self::check_response_id(request_id, response_id),
-            PermissionDenied {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::PermissionDenied(reason))
-            }
-            Failure {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::RequestFailure(reason))
-            }
-            response => Err(Error::invalid_server_response(
-                &"Ok, PermissionDenied or Failure",
-                &response,
-            )),
+        self.wait_for_ok(request_id).await
+    }
+
+    /// Send all `forwards` back-to-back and then wait for all of their
+    /// responses, which saves a round trip per forward compared to
+    /// [`Connection::request_port_forward`] and
+    /// [`Connection::close_port_forward`].
+    ///
+    /// Return the result of each forward in the same order as `forwards`.
+    ///
+    /// The outer `Result` is an error only if the connection itself fails,
+    /// in which case the results of individual forwards are lost.
+    ///
+    /// Unlike other methods taking `&mut self`, this method is not
+    /// cancellation safe.
+    pub async fn request_forwards(
+        &mut self,
+        forwards: &[ForwardRequest<'_>],
+    ) -> Result<Vec<Result<()>>> {
+        let request_ids = self.protocol.send_forwards(forwards)?;
+        self.flush(&[]).await?;
+
+        let mut results = BatchResults::new(request_ids);
+        while !results.is_done() {
+            results.handle_event(self.next_event().await?)?;
         }
+
+        Ok(results.into_results())
     }
 
     /// **UNTESTED** Return remote port opened for dynamic forwarding.
@@ -527,73 +428,31 @@ impl Connection {
         &mut self,
         listen_socket: &Socket<'_>,
     ) -> Result<NonZeroU32> {
-        use Response::*;
-
-        let fwd = Fwd::Dynamic { listen_socket };
-
-        let request_id = self.get_request_id();
-        self.send_fwd_request(request_id, &fwd).await?;
+        let request_id = self.protocol.send_dynamic_forward(listen_socket)?;
 
-        match self.read_response().await? {
-            RemotePort {
-                response_id,
-                remote_port,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                NonZeroU32::new(remote_port).ok_or(Error::InvalidPort)
-            }
-            PermissionDenied {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::PermissionDenied(reason))
-            }
-            Failure {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::RequestFailure(reason))
-            }
-            response => Err(Error::invalid_server_response(
-                &"RemotePort, PermissionDenied or Failure",
-                &response,
-            )),
+        match self.wait_for_reply(request_id, &[]).await? {
+            Reply::RemotePort { port } => Ok(port),
+            reply => unreachable!("Unexpected reply {:?}", reply),
         }
     }
 
     /// Request the master to stop accepting new multiplexing requests
     /// and remove its listener socket.
     pub async fn request_stop_listening(&mut self) -> Result<()> {
-        use Response::*;
+        let request_id = self.protocol.send_stop_listening()?;
 
-        let request_id = self.get_request_id();
-        self.write(&Request::StopListening { request_id }).await?;
+        self.wait_for_ok(request_id).await
+    }
 
-        match self.read_response().await? {
-            Ok { response_id } => {
-                Self::check_response_id(request_id, response_id)?;
-                Result::Ok(())
-            }
-            PermissionDenied {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::PermissionDenied(reason))
-            }
-            Failure {
-                response_id,
-                reason,
-            } => {
-                Self::check_response_id(request_id, response_id)?;
-                Err(Error::RequestFailure(reason))
-            }
-            response => Err(Error::invalid_server_response(
-                &"Ok, PermissionDenied or Failure",
-                &response,
-            )),
+    /// Request the master to terminate, closing all of its sessions.
+    ///
+    /// The master might exit before replying, which is not an error.
+    pub async fn request_terminate(mut self) -> Result<()> {
+        let request_id = self.protocol.send_terminate()?;
+
+        match self.wait_for_ok(request_id).await {
+            Err(err) if is_closed_by_master(&err) => Ok(()),
+            res => res,
         }
     }
 
@@ -601,15 +460,38 @@ impl Connection {
     /// and remove its listener socket.
     ///
     /// **Only suitable to use in `Drop::drop`.**
-    pub fn request_stop_listening_sync(self) -> Result<()> {
-        shutdown_mux_master_from(self.raw_conn.into_std()?)
+    pub fn request_stop_listening_sync(mut self) -> Result<()> {
+        let raw_conn = self.raw_conn.into_std()?;
+        raw_conn.set_nonblocking(false)?;
+
+        blocking_io::request_stop_listening(&raw_conn, &mut self.protocol)
     }
 }
 
+/// Return true if `err` is caused by the master closing the connection.
+pub(crate) fn is_closed_by_master(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::IOError(err) if matches!(
+            err.kind(),
+            io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::BrokenPipe
+        )
+    )
+}
+
+/// Create a session running subsystem `name`.
+pub(crate) fn subsystem_session(name: &NonZeroByteSlice) -> Session<'_> {
+    Session::builder()
+        .subsystem(true)
+        .term(Cow::Borrowed("".try_into().unwrap()))
+        .cmd(Cow::Borrowed(name))
+        .build()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::SessionStatus;
+    use crate::{constants, SessionStatus};
 
     use std::convert::TryInto;
     use std::env;
@@ -718,6 +600,67 @@ mod tests {
     }
     run_test!(test_unordered_open_new_session, test_open_new_session_impl);
 
+    async fn test_open_new_session_piped_impl(conn: Connection) {
+        let session = Session::builder()
+            .cmd(Cow::Borrowed("/bin/cat".try_into().unwrap()))
+            .build();
+
+        let (established_session, mut remote_child) =
+            conn.open_new_session_piped(&session).await.unwrap();
+
+        let data = b"0134131dqwdqdx13as\n";
+
+        let mut stdin = remote_child.stdin.take().unwrap();
+        stdin.write_all(data).await.unwrap();
+        drop(stdin);
+
+        let mut buffer = Vec::new();
+        let mut stdout = remote_child.stdout.take().unwrap();
+        stdout.read_to_end(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, data);
+
+        let session_status = established_session.wait().await.unwrap();
+        assert_matches!(
+            session_status,
+            SessionStatus::Exited { exit_value, .. }
+                if exit_value.unwrap() == 0
+        );
+    }
+    run_test!(
+        test_unordered_open_new_session_piped,
+        test_open_new_session_piped_impl
+    );
+
+    async fn test_subsystem_piped_impl(conn: Connection) {
+        let (established_session, mut stdin, mut stdout) = conn
+            .subsystem_piped("sftp".try_into().unwrap())
+            .await
+            .unwrap();
+
+        const SSH_FXP_INIT: u8 = 1;
+        const SSH_FXP_VERSION: u8 = 2;
+
+        // Packet length, type and version 3
+        stdin
+            .write_all(&[0, 0, 0, 5, SSH_FXP_INIT, 0, 0, 0, 3])
+            .await
+            .unwrap();
+
+        let len = stdout.read_u32().await.unwrap();
+        let mut packet = vec![0; len as usize];
+        stdout.read_exact(&mut packet).await.unwrap();
+        assert_eq!(packet[0], SSH_FXP_VERSION);
+
+        drop(stdin);
+        drop(stdout);
+
+        assert_matches!(
+            established_session.wait().await.unwrap(),
+            SessionStatus::Exited { .. }
+        );
+    }
+    run_test!(test_unordered_subsystem_piped, test_subsystem_piped_impl);
+
     async fn test_remote_socket_forward_impl(mut conn0: Connection, mut conn1: Connection) {
         let path = Path::new("/tmp/openssh-remote-forward.socket");
 
@@ -850,6 +793,60 @@ mod tests {
         test_local_socket_forward_impl
     );
 
+    async fn test_request_forwards_impl(mut conn: Connection) {
+        let sockets: Vec<_> = (1236..1239)
+            .map(|port| Socket::TcpSocket {
+                port,
+                host: "127.0.0.1".into(),
+            })
+            .collect();
+        let connect_socket = Socket::UnixSocket {
+            path: Path::new("/tmp/openssh-batch-forward.socket").into(),
+        };
+
+        let open = |listen_socket| ForwardRequest::Open {
+            forward_type: ForwardType::Local,
+            listen_socket,
+            connect_socket: &connect_socket,
+        };
+        let close = |listen_socket| ForwardRequest::Close {
+            forward_type: ForwardType::Local,
+            listen_socket,
+            connect_socket: &connect_socket,
+        };
+
+        eprintln!("Requesting port forwards");
+        let results = conn
+            .request_forwards(&[open(&sockets[0]), open(&sockets[1]), close(&sockets[2])])
+            .await
+            .unwrap();
+        assert_matches!(
+            results.as_slice(),
+            [Ok(()), Ok(()), Err(Error::RequestFailure(_))]
+        );
+
+        TcpStream::connect(("127.0.0.1", 1236)).await.unwrap();
+        TcpStream::connect(("127.0.0.1", 1237)).await.unwrap();
+
+        eprintln!("Closing port forwards");
+        let results = conn
+            .request_forwards(&[close(&sockets[0]), close(&sockets[1])])
+            .await
+            .unwrap();
+        assert_matches!(results.as_slice(), [Ok(()), Ok(())]);
+
+        // Forwards in the same batch do not get coalesced
+        let results = conn
+            .request_forwards(&[open(&sockets[2]), open(&sockets[2])])
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        conn.close_port_forward(ForwardType::Local, &sockets[2], &connect_socket)
+            .await
+            .unwrap();
+    }
+    run_test!(test_unordered_request_forwards, test_request_forwards_impl);
+
     async fn test_request_stop_listening_impl(mut conn: Connection) {
         conn.request_stop_listening().await.unwrap();
 
@@ -866,4 +863,61 @@ mod tests {
         test_request_stop_listening,
         test_request_stop_listening_impl
     );
+
+    async fn read_packet(stream: &mut UnixStream) -> Vec<u8> {
+        let len = stream.read_u32().await.unwrap();
+        let mut packet = vec![0; len as usize];
+        stream.read_exact(&mut packet).await.unwrap();
+        packet
+    }
+
+    async fn write_packet(stream: &mut UnixStream, body: &[u32]) {
+        stream.write_u32(body.len() as u32 * 4).await.unwrap();
+        for int in body {
+            stream.write_u32(*int).await.unwrap();
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unordered_cancel_safety() {
+        let path = Path::new("/tmp/openssh-mux-client-cancel-test.socket");
+        let _ = std::fs::remove_file(path);
+        let listener = tokio::net::UnixListener::bind(path).unwrap();
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let server = tokio::spawn(async move {
+            let (mut stream, _addr) = listener.accept().await.unwrap();
+
+            read_packet(&mut stream).await;
+            write_packet(
+                &mut stream,
+                &[constants::MUX_MSG_HELLO, constants::SSHMUX_VER],
+            )
+            .await;
+
+            let packet = read_packet(&mut stream).await;
+            let request_id = u32::from_be_bytes(packet[4..8].try_into().unwrap());
+
+            // Only reply once the client has cancelled the request
+            rx.await.unwrap();
+            write_packet(&mut stream, &[constants::MUX_S_ALIVE, request_id, 1234]).await;
+
+            // The retried request must not be sent again
+            assert_eq!(stream.read(&mut [0]).await.unwrap(), 0);
+        });
+
+        let mut conn = Connection::connect(path).await.unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        tokio::time::timeout(Duration::from_millis(100), conn.send_alive_check())
+            .await
+            .unwrap_err();
+
+        tx.send(()).unwrap();
+        assert_eq!(conn.send_alive_check().await.unwrap().get(), 1234);
+
+        drop(conn);
+        server.await.unwrap();
+    }
 }
@@ -0,0 +1,337 @@
+#![forbid(unsafe_code)]
+
+//! Expansion of the `ControlPath` tokens understood by `ssh(1)` and
+//! discovery of live multiplex masters (enabled by feature `master`).
+
+use super::{Error, Result};
+
+use std::{borrow::Cow, env, fmt::Write, path::PathBuf};
+
+use once_cell::sync::OnceCell;
+use sha1_smol::Sha1;
+use typed_builder::TypedBuilder;
+
+#[cfg(feature = "master")]
+use super::Connection;
+#[cfg(feature = "master")]
+use std::{num::NonZeroU32, os::unix::fs::FileTypeExt, panic, path::Path, time::Duration};
+#[cfg(feature = "master")]
+use tokio::{fs, task::JoinSet, time::timeout};
+
+/// Values substituted for the `%` tokens of `ControlPath`.
+///
+/// Fields describing the local machine default to the values `ssh(1)`
+/// would use for the current process.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, TypedBuilder)]
+#[builder(doc)]
+pub struct ControlPathParams<'a> {
+    /// `%h`: the remote host name, after `HostName` substitution.
+    #[builder(setter(into))]
+    pub host: Cow<'a, str>,
+
+    /// `%n`: the original remote host name as given on the command line.
+    ///
+    /// Defaults to `host` if not set.
+    #[builder(default, setter(strip_option, into))]
+    pub original_host: Option<Cow<'a, str>>,
+
+    /// `%k`: the host key alias if specified.
+    ///
+    /// Defaults to `original_host` if not set.
+    #[builder(default, setter(strip_option, into))]
+    pub host_key_alias: Option<Cow<'a, str>>,
+
+    /// `%p`: the remote port.
+    #[builder(default = 22)]
+    pub port: u16,
+
+    /// `%r`: the remote user name.
+    ///
+    /// Defaults to `local_user` if not set.
+    #[builder(default, setter(strip_option, into))]
+    pub remote_user: Option<Cow<'a, str>>,
+
+    /// `%j`: the contents of the `ProxyJump` option.
+    #[builder(default, setter(into))]
+    pub proxy_jump: Cow<'a, str>,
+
+    /// `%u`: the local user name.
+    #[builder(default_code = r#"Cow::Borrowed(get_local_user())"#, setter(into))]
+    pub local_user: Cow<'a, str>,
+
+    /// `%l`: the local host name, including any domain name.
+    ///
+    /// `%L` is derived from it by removing the domain name.
+    #[builder(default_code = r#"Cow::Borrowed(get_local_host())"#, setter(into))]
+    pub local_host: Cow<'a, str>,
+
+    /// `%d`: the local user's home directory.
+    #[builder(default_code = r#"Cow::Borrowed(get_home_dir())"#, setter(into))]
+    pub home_dir: Cow<'a, str>,
+
+    /// `%i`: the local user id.
+    #[builder(default_code = r#"rustix::process::getuid().as_raw()"#)]
+    pub uid: u32,
+}
+
+impl ControlPathParams<'_> {
+    fn original_host(&self) -> &str {
+        self.original_host.as_deref().unwrap_or(&self.host)
+    }
+
+    fn remote_user(&self) -> &str {
+        self.remote_user.as_deref().unwrap_or(&self.local_user)
+    }
+
+    /// Return `%C`, the hex encoded SHA1 hash of `%l%h%p%r%j`.
+    pub fn connection_hash(&self) -> String {
+        let mut hasher = Sha1::new();
+
+        hasher.update(self.local_host.as_bytes());
+        hasher.update(self.host.as_bytes());
+        hasher.update(self.port.to_string().as_bytes());
+        hasher.update(self.remote_user().as_bytes());
+        hasher.update(self.proxy_jump.as_bytes());
+
+        hasher.digest().to_string()
+    }
+
+    /// Expand `control_path` the same way `ssh(1)` does: a leading `~/`
+    /// is replaced with the home directory and every `%` token is
+    /// substituted.
+    ///
+    /// Return an error on unknown tokens or a trailing `%`.
+    pub fn expand(&self, control_path: &str) -> Result<PathBuf> {
+        let mut expanded = String::with_capacity(control_path.len());
+
+        let rest = match control_path.strip_prefix('~') {
+            Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                expanded.push_str(&self.home_dir);
+                rest
+            }
+            _ => control_path,
+        };
+
+        let mut chars = rest.chars();
+
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                expanded.push(c);
+                continue;
+            }
+
+            let token = chars.next().ok_or_else(|| {
+                Error::InvalidControlPath(format!("trailing '%' in {:?}", control_path).into())
+            })?;
+
+            match token {
+                '%' => expanded.push('%'),
+                'C' => expanded.push_str(&self.connection_hash()),
+                'd' => expanded.push_str(&self.home_dir),
+                'h' => expanded.push_str(&self.host),
+                'i' => write!(expanded, "{}", self.uid).unwrap(),
+                'j' => expanded.push_str(&self.proxy_jump),
+                'k' => expanded.push_str(
+                    self.host_key_alias
+                        .as_deref()
+                        .unwrap_or_else(|| self.original_host()),
+                ),
+                'L' => expanded.push_str(
+                    self.local_host
+                        .split('.')
+                        .next()
+                        .unwrap_or(&self.local_host),
+                ),
+                'l' => expanded.push_str(&self.local_host),
+                'n' => expanded.push_str(self.original_host()),
+                'p' => write!(expanded, "{}", self.port).unwrap(),
+                'r' => expanded.push_str(self.remote_user()),
+                'u' => expanded.push_str(&self.local_user),
+                token => {
+                    return Err(Error::InvalidControlPath(
+                        format!("unknown token '%{}' in {:?}", token, control_path).into(),
+                    ))
+                }
+            }
+        }
+
+        Ok(expanded.into())
+    }
+}
+
+/// Return environment variable `$USER` (or `$LOGNAME`) if set.
+/// Otherwise, returns empty string.
+fn get_local_user() -> &'static str {
+    static USER: OnceCell<String> = OnceCell::new();
+    USER.get_or_init(|| {
+        env::var("USER")
+            .or_else(|_| env::var("LOGNAME"))
+            .unwrap_or_default()
+    })
+}
+
+/// Return environment variable `$HOME` if set.
+/// Otherwise, returns empty string.
+fn get_home_dir() -> &'static str {
+    static HOME: OnceCell<String> = OnceCell::new();
+    HOME.get_or_init(|| env::var("HOME").unwrap_or_default())
+}
+
+/// Return host name of the local machine.
+fn get_local_host() -> &'static str {
+    static HOST: OnceCell<String> = OnceCell::new();
+    HOST.get_or_init(|| {
+        rustix::system::uname()
+            .nodename()
+            .to_string_lossy()
+            .into_owned()
+    })
+}
+
+/// A multiplex master that responded to an alive check.
+#[cfg(feature = "master")]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct LiveMaster {
+    /// Path to the control socket.
+    pub path: PathBuf,
+
+    /// Pid of the ssh multiplex master.
+    pub pid: NonZeroU32,
+}
+
+/// Scan `dir` for unix sockets, connect to each of them and return the ones
+/// that respond to [`Connection::send_alive_check`] within `probe_timeout`.
+///
+/// Sockets are probed concurrently. Sockets that fail to connect or
+/// to respond in time (e.g. stale sockets left behind by a dead master,
+/// or sockets of other programs) are skipped, so are entries removed
+/// during the scan.
+///
+/// The masters found are sorted by path.
+#[cfg(feature = "master")]
+pub async fn find_live_masters<P: AsRef<Path>>(
+    dir: P,
+    probe_timeout: Duration,
+) -> Result<Vec<LiveMaster>> {
+    let mut probes = JoinSet::new();
+    let mut entries = fs::read_dir(dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        match entry.file_type().await {
+            Ok(file_type) if file_type.is_socket() => (),
+            _ => continue,
+        }
+
+        let path = entry.path();
+
+        probes.spawn(async move {
+            let probe = async { Connection::connect(&path).await?.send_alive_check().await };
+
+            match timeout(probe_timeout, probe).await {
+                Ok(Ok(pid)) => Some(LiveMaster { path, pid }),
+                _ => None,
+            }
+        });
+    }
+
+    let mut live_masters = Vec::new();
+
+    while let Some(res) = probes.join_next().await {
+        // Probes are never cancelled, so the error must be a panic.
+        let res = res.unwrap_or_else(|err| panic::resume_unwind(err.into_panic()));
+
+        if let Some(live_master) = res {
+            live_masters.push(live_master);
+        }
+    }
+
+    live_masters.sort_unstable_by(|x, y| x.path.cmp(&y.path));
+
+    Ok(live_masters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    fn params() -> ControlPathParams<'static> {
+        ControlPathParams::builder()
+            .host("example.com")
+            .original_host("example")
+            .port(2222)
+            .remote_user("root")
+            .local_user("alice")
+            .local_host("laptop.local")
+            .home_dir("/home/alice")
+            .uid(1000)
+            .build()
+    }
+
+    #[test]
+    fn test_expand() {
+        assert_eq!(
+            params()
+                .expand("~/.ssh/%r@%h:%p-%n-%k-%u-%i-%L-%l-%d-%%")
+                .unwrap(),
+            Path::new(
+                "/home/alice/.ssh/root@example.com:2222-example-example-alice-1000-\
+                 laptop-laptop.local-/home/alice-%"
+            ),
+        );
+    }
+
+    #[test]
+    fn test_connection_hash() {
+        // sha1("laptop.localexample.com2222root")
+        assert_eq!(
+            params().expand("/tmp/%C").unwrap(),
+            Path::new("/tmp/ee99fcbf247a530eb264d5180affefa482728fc2"),
+        );
+    }
+
+    #[test]
+    fn test_invalid_token() {
+        assert_matches!(
+            params().expand("/tmp/%z"),
+            Err(Error::InvalidControlPath(_))
+        );
+        assert_matches!(params().expand("/tmp/%"), Err(Error::InvalidControlPath(_)));
+    }
+
+    #[cfg(feature = "master")]
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_find_live_masters_skips_unresponsive_sockets() {
+        let dir = env::temp_dir().join(format!(
+            "openssh-mux-client-find-live-masters-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+
+        std::fs::write(dir.join("regular-file"), b"").unwrap();
+
+        // Accepts connections but never replies
+        let listener = tokio::net::UnixListener::bind(dir.join("silent.socket")).unwrap();
+        let server = tokio::spawn(async move {
+            let mut streams = Vec::new();
+            loop {
+                streams.push(listener.accept().await.unwrap().0);
+            }
+        });
+
+        let live_masters = timeout(
+            Duration::from_secs(5),
+            find_live_masters(&dir, Duration::from_millis(100)),
+        )
+        .await
+        .expect("The scan should not hang on unresponsive sockets")
+        .unwrap();
+
+        assert_eq!(live_masters, []);
+
+        server.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+#![forbid(unsafe_code)]
+
+use super::{Connection, Result};
+
+use std::{
+    convert::TryInto,
+    io,
+    num::NonZeroU32,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use rustix::{
+    io::Errno,
+    process::{test_kill_process, Pid},
+};
+use tokio::time::sleep;
+
+/// Interval of polling whether the master has exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How the master exited in [`Connection::graceful_shutdown`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ShutdownOutcome {
+    /// The master exited before the deadline.
+    Exited,
+
+    /// The deadline passed and the master is requested to terminate.
+    Terminated,
+}
+
+impl Connection {
+    /// Request the master to stop listening, then give existing
+    /// connections and sessions until `deadline` to finish, after which
+    /// the master is requested to terminate with `MUX_C_TERMINATE`.
+    ///
+    /// * `path` - path of the control socket this connection is made to.
+    ///
+    /// The pid returned by [`Connection::send_alive_check`] is polled to
+    /// find out whether the master has exited, so the master must be in
+    /// the same pid namespace and be reaped by its parent once it exits.
+    /// [`ShutdownOutcome::Exited`] is only returned if the master exits
+    /// before the deadline.
+    ///
+    /// Since no new connection can be made once the master stops
+    /// listening, a second connection is made beforehand to send
+    /// `MUX_C_TERMINATE`, while this connection is closed. ssh counts the
+    /// second connection as an active one, so the master only exits before
+    /// the deadline for other reasons, e.g. its connection to the server
+    /// is closed.
+    pub async fn graceful_shutdown<P: AsRef<Path>>(
+        mut self,
+        path: P,
+        deadline: Instant,
+    ) -> Result<ShutdownOutcome> {
+        let pid = self.send_alive_check().await?;
+        let terminate_conn = Self::connect(path).await?;
+
+        self.request_stop_listening().await?;
+        drop(self);
+
+        if wait_for_exit(pid, deadline).await? {
+            return Ok(ShutdownOutcome::Exited);
+        }
+
+        terminate_conn
+            .request_terminate()
+            .await
+            .map(|()| ShutdownOutcome::Terminated)
+    }
+}
+
+/// Poll the process with `pid` until it exits, return `false` if it is
+/// still running at `deadline`.
+async fn wait_for_exit(pid: NonZeroU32, deadline: Instant) -> Result<bool> {
+    let pid = pid
+        .get()
+        .try_into()
+        .ok()
+        .and_then(Pid::from_raw)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid pid of the master"))?;
+
+    loop {
+        match test_kill_process(pid) {
+            Err(Errno::SRCH) => break Ok(true),
+            // The process exists but is owned by another user
+            Ok(()) | Err(Errno::PERM) => (),
+            Err(errno) => break Err(io::Error::from(errno).into()),
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            break Ok(false);
+        }
+
+        sleep(POLL_INTERVAL.min(deadline - now)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MasterBuilder;
+
+    use rustix::process::{kill_process, Signal};
+
+    const STUB_SSH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../testfiles/stub_ssh");
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_graceful_shutdown_exited() {
+        let path = Path::new("/tmp/openssh-mux-client-shutdown-exited.socket");
+
+        let mut master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+        let pid = Pid::from_raw(master.id().unwrap().try_into().unwrap()).unwrap();
+
+        let mut session = Connection::connect(path).await.unwrap();
+        let conn = Connection::connect(path).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+
+        let (outcome, status) = tokio::join!(conn.graceful_shutdown(path, deadline), async {
+            sleep(Duration::from_millis(200)).await;
+
+            // The master stops listening, but the session is still usable
+            // until it ends before the deadline.
+            assert!(!path.exists());
+            session.send_alive_check().await.unwrap();
+            drop(session);
+
+            // The master is still running
+            sleep(Duration::from_millis(200)).await;
+            test_kill_process(pid).unwrap();
+
+            // The master exits on its own, e.g. on losing its connection
+            // to the server.
+            kill_process(pid, Signal::TERM).unwrap();
+            master.wait().await
+        });
+
+        assert_eq!(outcome.unwrap(), ShutdownOutcome::Exited);
+        assert!(status.unwrap().success());
+        assert!(Instant::now() < deadline);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_graceful_shutdown_terminated() {
+        let path = Path::new("/tmp/openssh-mux-client-shutdown-terminated.socket");
+
+        let mut master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+
+        let mut other_conn = Connection::connect(path).await.unwrap();
+        let conn = Connection::connect(path).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_millis(200);
+        let outcome = conn.graceful_shutdown(path, deadline).await.unwrap();
+
+        assert_eq!(outcome, ShutdownOutcome::Terminated);
+        assert!(Instant::now() >= deadline);
+        assert!(master.wait().await.unwrap().success());
+
+        // Other connections are closed by the master
+        other_conn.send_alive_check().await.unwrap_err();
+    }
+}
@@ -7,28 +7,62 @@ pub use error::Error;
 pub use openssh_mux_client_error as error;
 pub type Result<T, Err = Error> = std::result::Result<T, Err>;
 
+use std::fmt::Debug;
+
 trait ErrorExt {
-    fn invalid_server_response(package_type: &'static &'static str, response: &Response) -> Self;
+    fn invalid_server_response(package_type: &'static &'static str, response: &dyn Debug) -> Self;
 }
 
 impl ErrorExt for Error {
-    fn invalid_server_response(package_type: &'static &'static str, response: &Response) -> Self {
+    fn invalid_server_response(package_type: &'static &'static str, response: &dyn Debug) -> Self {
         Error::InvalidServerResponse(package_type, format!("{:#?}", response).into_boxed_str())
     }
 }
 
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+mod blocking_io;
+
 pub mod default_config;
 
+pub mod control_path;
+
+mod command;
+pub use command::{Command, Output, Stdio};
+
 mod connection;
 pub use connection::*;
 
 mod constants;
 
+#[cfg(feature = "master")]
+mod graceful_shutdown;
+#[cfg(feature = "master")]
+pub use graceful_shutdown::ShutdownOutcome;
+
+#[cfg(feature = "master")]
+mod master;
+#[cfg(feature = "master")]
+pub use master::{Master, MasterBuilder};
+
+#[cfg(feature = "monitor")]
+mod monitor;
+#[cfg(feature = "monitor")]
+pub use monitor::{MasterMonitor, MasterMonitorBuilder, MasterState};
+
+#[cfg(feature = "pool")]
+mod pool;
+#[cfg(feature = "pool")]
+pub use pool::{ConnectionPool, ConnectionPoolBuilder, PooledConnection};
+
+pub mod proto;
+
 mod request;
 pub use request::{Session, Socket};
 
 mod response;
-pub use response::Response;
+pub use response::{Extension, Response};
 
 mod session;
 pub use session::*;
@@ -36,6 +70,9 @@ pub use session::*;
 mod shutdown_mux_master;
 pub use shutdown_mux_master::shutdown_mux_master;
 
+#[cfg(feature = "tty")]
+pub mod tty;
+
 mod utils;
 
 #[cfg(test)]
@@ -0,0 +1,275 @@
+#![forbid(unsafe_code)]
+
+use super::{Connection, Error, Result};
+
+use std::{
+    convert::TryInto,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    process::{ExitStatus, Stdio},
+    time::Duration,
+};
+
+use rustix::process::{kill_process, Pid, Signal};
+use tokio::{
+    fs,
+    process::{Child, Command},
+    time::{sleep, timeout},
+};
+
+/// Builder for spawning `ssh -o ControlMaster=yes -S control_path -N destination`.
+#[derive(Clone, Debug)]
+pub struct MasterBuilder {
+    ssh_binary: PathBuf,
+    destination: OsString,
+    control_path: PathBuf,
+    args: Vec<OsString>,
+    timeout: Duration,
+    poll_interval: Duration,
+}
+
+impl MasterBuilder {
+    /// * `destination` - passed to ssh as is, e.g. `user@host`.
+    /// * `control_path` - path of the control socket the master
+    ///   should listen on.
+    pub fn new<D, P>(destination: D, control_path: P) -> Self
+    where
+        D: Into<OsString>,
+        P: Into<PathBuf>,
+    {
+        Self {
+            ssh_binary: "ssh".into(),
+            destination: destination.into(),
+            control_path: control_path.into(),
+            args: Vec::new(),
+            timeout: Duration::from_secs(30),
+            poll_interval: Duration::from_millis(50),
+        }
+    }
+
+    /// Set the ssh binary to use, defaults to `ssh` looked up in `$PATH`.
+    pub fn ssh_binary<P: Into<PathBuf>>(&mut self, ssh_binary: P) -> &mut Self {
+        self.ssh_binary = ssh_binary.into();
+        self
+    }
+
+    /// Pass `-o key=value` to ssh.
+    pub fn option(&mut self, key: &str, value: &str) -> &mut Self {
+        self.args.push("-o".into());
+        self.args.push(format!("{}={}", key, value).into());
+        self
+    }
+
+    /// Pass an arbitrary argument to ssh, it is put before the destination.
+    pub fn arg<A: Into<OsString>>(&mut self, arg: A) -> &mut Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Maximum time to wait for the master to accept connections,
+    /// defaults to 30s.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Interval between attempts to connect to the control socket,
+    /// defaults to 50ms.
+    pub fn poll_interval(&mut self, poll_interval: Duration) -> &mut Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Spawn the ssh multiplex master and wait until [`Connection::connect`]
+    /// succeeds on the control socket.
+    ///
+    /// If the master exits or does not become ready before the timeout,
+    /// it is killed and an error is returned.
+    ///
+    /// Return [`Error::ControlPathExists`] if `control_path` already
+    /// exists, since ssh would not replace the master listening on it.
+    pub async fn launch(&self) -> Result<Master> {
+        if fs::symlink_metadata(&self.control_path).await.is_ok() {
+            return Err(Error::ControlPathExists(self.control_path.clone()));
+        }
+
+        let child = Command::new(&self.ssh_binary)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .arg("-o")
+            .arg("ControlMaster=yes")
+            .arg("-S")
+            .arg(&self.control_path)
+            .arg("-N")
+            .args(&self.args)
+            .arg(&self.destination)
+            .spawn()?;
+
+        let mut master = Master {
+            child,
+            control_path: self.control_path.clone(),
+        };
+
+        match timeout(self.timeout, master.wait_until_ready(self.poll_interval)).await {
+            Ok(Ok(())) => Ok(master),
+            Ok(Err(err)) => Err(err),
+            Err(_elapsed) => Err(Error::MasterLaunchTimeout),
+        }
+    }
+}
+
+/// Handle to a running ssh multiplex master spawned by [`MasterBuilder`].
+///
+/// The master is terminated and reaped on drop.
+#[derive(Debug)]
+pub struct Master {
+    child: Child,
+    control_path: PathBuf,
+}
+
+impl Master {
+    async fn wait_until_ready(&mut self, poll_interval: Duration) -> Result<()> {
+        loop {
+            if let Some(exit_status) = self.child.try_wait()? {
+                break Err(Error::MasterExited(exit_status));
+            }
+
+            if let Ok(mut connection) = Connection::connect(&self.control_path).await {
+                // Another master might have taken `control_path` after
+                // `launch` checked it.
+                let pid = connection.send_alive_check().await?;
+
+                break if Some(pid.get()) == self.child.id() {
+                    Ok(())
+                } else {
+                    Err(Error::ControlPathExists(self.control_path.clone()))
+                };
+            }
+
+            sleep(poll_interval).await;
+        }
+    }
+
+    /// Path to the control socket.
+    pub fn control_path(&self) -> &Path {
+        &self.control_path
+    }
+
+    /// Return pid of the master or `None` if it has been reaped.
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    /// Open a new [`Connection`] to the master.
+    pub async fn connect(&self) -> Result<Connection> {
+        Connection::connect(&self.control_path).await
+    }
+
+    /// Request the master to stop accepting new multiplexing requests
+    /// and remove its listener socket.
+    ///
+    /// The master exits once all existing sessions are closed, use
+    /// [`Master::wait`] to wait for it.
+    pub async fn request_stop_listening(&self) -> Result<()> {
+        self.connect().await?.request_stop_listening().await
+    }
+
+    /// Wait for the master to exit.
+    pub async fn wait(&mut self) -> Result<ExitStatus> {
+        Ok(self.child.wait().await?)
+    }
+
+    /// Send `SIGTERM` to the master and wait for it to exit.
+    pub async fn terminate(mut self) -> Result<ExitStatus> {
+        self.send_sigterm()?;
+        self.wait().await
+    }
+
+    fn send_sigterm(&self) -> io::Result<()> {
+        let pid = self
+            .id()
+            .and_then(|pid| pid.try_into().ok())
+            .and_then(Pid::from_raw);
+
+        match pid {
+            Some(pid) => Ok(kill_process(pid, Signal::TERM)?),
+            // The child has already been reaped
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Master {
+    fn drop(&mut self) {
+        // Use `SIGTERM` instead of `SIGKILL` so that ssh can remove
+        // the control socket before exiting.
+        if self.send_sigterm().is_ok() {
+            // If the master has not exited yet, tokio would reap it
+            // in the background once `Child` is dropped.
+            self.child.try_wait().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STUB_SSH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../testfiles/stub_ssh");
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_master_builder() {
+        let path = Path::new("/tmp/openssh-mux-client-stub-master.socket");
+
+        let mut master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+
+        let pid = master.connect().await.unwrap().send_alive_check().await;
+        assert_eq!(pid.unwrap().get(), master.id().unwrap());
+
+        master.request_stop_listening().await.unwrap();
+        assert!(master.wait().await.unwrap().success());
+        assert!(!path.exists());
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_master_builder_control_path_exists() {
+        let path = Path::new("/tmp/openssh-mux-client-stub-master-exists.socket");
+
+        let master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+
+        let res = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await;
+        assert_matches!(res, Err(Error::ControlPathExists(p)) if p == path);
+
+        // The existing master is left untouched
+        master
+            .connect()
+            .await
+            .unwrap()
+            .send_alive_check()
+            .await
+            .unwrap();
+        master.terminate().await.unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_master_builder_exited() {
+        let res = MasterBuilder::new("localhost", "/tmp/openssh-mux-client-false.socket")
+            .ssh_binary("false")
+            .launch()
+            .await;
+
+        assert_matches!(res, Err(Error::MasterExited(exit_status)) if !exit_status.success());
+    }
+}
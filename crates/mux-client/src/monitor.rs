@@ -0,0 +1,345 @@
+#![forbid(unsafe_code)]
+
+use super::{Connection, Error, Result};
+
+use std::{
+    fmt,
+    future::Future,
+    io,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+    time::{interval, timeout, MissedTickBehavior},
+};
+
+type RelaunchFuture = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+type RelaunchFn = dyn Fn() -> RelaunchFuture + Send + Sync;
+
+/// State of the ssh multiplex master published by [`MasterMonitor`].
+#[derive(Clone, Debug)]
+pub enum MasterState {
+    /// The master responded to the alive check.
+    Alive { pid: NonZeroU32 },
+
+    /// The master responded to the alive check, but with a different pid
+    /// than the last one seen, e.g. it has been relaunched.
+    PidChanged { old: NonZeroU32, new: NonZeroU32 },
+
+    /// The master cannot be reached, either connecting to it or the
+    /// alive check failed or timed out.
+    Unreachable(Arc<Error>),
+}
+
+impl MasterState {
+    /// Return pid of the master if it is reachable.
+    pub fn pid(&self) -> Option<NonZeroU32> {
+        match self {
+            MasterState::Alive { pid } => Some(*pid),
+            MasterState::PidChanged { new, .. } => Some(*new),
+            MasterState::Unreachable(_) => None,
+        }
+    }
+}
+
+/// Builder for [`MasterMonitor`].
+#[derive(Clone)]
+pub struct MasterMonitorBuilder {
+    control_path: PathBuf,
+    interval: Duration,
+    relaunch: Option<Arc<RelaunchFn>>,
+}
+
+impl fmt::Debug for MasterMonitorBuilder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MasterMonitorBuilder")
+            .field("control_path", &self.control_path)
+            .field("interval", &self.interval)
+            .field("relaunch", &self.relaunch.is_some())
+            .finish()
+    }
+}
+
+impl MasterMonitorBuilder {
+    /// * `control_path` - path to the control socket of the master.
+    pub fn new<P: Into<PathBuf>>(control_path: P) -> Self {
+        Self {
+            control_path: control_path.into(),
+            interval: Duration::from_secs(10),
+            relaunch: None,
+        }
+    }
+
+    /// Interval between alive checks, defaults to 10s.
+    ///
+    /// It is also used as the timeout of each alive check.
+    pub fn interval(&mut self, interval: Duration) -> &mut Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Set a callback to be called when the master becomes unreachable.
+    ///
+    /// It should relaunch the master on the same control path, e.g. using
+    /// [`MasterBuilder`](crate::MasterBuilder).
+    ///
+    /// It is called once every interval until the master is reachable
+    /// again, and its error, if any, is published as
+    /// [`MasterState::Unreachable`].
+    pub fn relaunch<F, Fut>(&mut self, relaunch: F) -> &mut Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        self.relaunch = Some(Arc::new(move || -> RelaunchFuture { Box::pin(relaunch()) }));
+        self
+    }
+
+    /// Connect to the master, run the first alive check and start
+    /// monitoring in a background task.
+    ///
+    /// Must be called in the context of a tokio runtime.
+    pub async fn start(&self) -> Result<MasterMonitor> {
+        let mut conn = Connection::connect(&self.control_path).await?;
+        let pid = conn.send_alive_check().await?;
+
+        let (sender, receiver) = watch::channel(MasterState::Alive { pid });
+
+        let task = Task {
+            control_path: self.control_path.clone(),
+            interval: self.interval,
+            relaunch: self.relaunch.clone(),
+            conn: Some(conn),
+            last_pid: pid,
+            sender,
+        };
+
+        Ok(MasterMonitor {
+            control_path: self.control_path.clone(),
+            receiver,
+            handle: tokio::spawn(task.run()),
+        })
+    }
+}
+
+struct Task {
+    control_path: PathBuf,
+    interval: Duration,
+    relaunch: Option<Arc<RelaunchFn>>,
+    conn: Option<Connection>,
+    last_pid: NonZeroU32,
+    sender: watch::Sender<MasterState>,
+}
+
+impl Task {
+    async fn alive_check(&mut self) -> Result<NonZeroU32> {
+        let conn = match &mut self.conn {
+            Some(conn) => conn,
+            None => self
+                .conn
+                .insert(Connection::connect(&self.control_path).await?),
+        };
+
+        conn.send_alive_check().await
+    }
+
+    async fn run(mut self) {
+        let mut ticker = interval(self.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        // The first tick completes immediately and the first alive check
+        // has been done in `MasterMonitorBuilder::start`.
+        ticker.tick().await;
+
+        loop {
+            ticker.tick().await;
+
+            let res = match timeout(self.interval, self.alive_check()).await {
+                Ok(res) => res,
+                Err(_elapsed) => Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Timed out waiting for response of alive check",
+                )
+                .into()),
+            };
+
+            match res {
+                Ok(pid) => {
+                    let last_pid = self.last_pid;
+                    self.last_pid = pid;
+
+                    self.sender.send_if_modified(|state| match state {
+                        MasterState::Alive { .. } | MasterState::PidChanged { .. }
+                            if pid == last_pid =>
+                        {
+                            false
+                        }
+                        _ => {
+                            *state = if pid == last_pid {
+                                MasterState::Alive { pid }
+                            } else {
+                                MasterState::PidChanged {
+                                    old: last_pid,
+                                    new: pid,
+                                }
+                            };
+                            true
+                        }
+                    });
+                }
+                Err(err) => {
+                    // The connection might be in an unknown state after
+                    // a failed or timed out request, so reconnect next time.
+                    self.conn = None;
+
+                    let err = match &self.relaunch {
+                        Some(relaunch) => relaunch().await.err().unwrap_or(err),
+                        None => err,
+                    };
+
+                    self.sender.send_if_modified(|state| {
+                        if matches!(state, MasterState::Unreachable(_)) {
+                            false
+                        } else {
+                            *state = MasterState::Unreachable(Arc::new(err));
+                            true
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Monitor an ssh multiplex master by running
+/// [`Connection::send_alive_check`] periodically in a background task.
+///
+/// The background task is aborted on drop.
+#[derive(Debug)]
+pub struct MasterMonitor {
+    control_path: PathBuf,
+    receiver: watch::Receiver<MasterState>,
+    handle: JoinHandle<()>,
+}
+
+impl MasterMonitor {
+    /// Shortcut for `MasterMonitorBuilder::new(control_path)`.
+    pub fn builder<P: Into<PathBuf>>(control_path: P) -> MasterMonitorBuilder {
+        MasterMonitorBuilder::new(control_path)
+    }
+
+    /// Path to the control socket being monitored.
+    pub fn control_path(&self) -> &Path {
+        &self.control_path
+    }
+
+    /// Return the latest state of the master.
+    pub fn state(&self) -> MasterState {
+        self.receiver.borrow().clone()
+    }
+
+    /// Subscribe to changes of the state of the master.
+    ///
+    /// The returned receiver is closed once the `MasterMonitor` is dropped.
+    pub fn subscribe(&self) -> watch::Receiver<MasterState> {
+        self.receiver.clone()
+    }
+}
+
+impl Drop for MasterMonitor {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+#[cfg(all(test, feature = "master"))]
+mod tests {
+    use super::*;
+    use crate::{Master, MasterBuilder};
+
+    use std::sync::Mutex;
+
+    const STUB_SSH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../testfiles/stub_ssh");
+
+    async fn launch(path: &Path) -> Master {
+        MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_master_monitor() {
+        let path = Path::new("/tmp/openssh-mux-client-monitor.socket");
+
+        let master = launch(path).await;
+        let old_pid = master.id().unwrap();
+
+        let masters = Arc::new(Mutex::new(Vec::new()));
+        let masters_cloned = masters.clone();
+
+        let monitor = MasterMonitor::builder(path)
+            .interval(Duration::from_millis(20))
+            .relaunch(move || {
+                let masters = masters_cloned.clone();
+                async move {
+                    let master = launch(Path::new("/tmp/openssh-mux-client-monitor.socket")).await;
+                    masters.lock().unwrap().push(master);
+                    Ok(())
+                }
+            })
+            .start()
+            .await
+            .unwrap();
+
+        assert_eq!(monitor.state().pid().unwrap().get(), old_pid);
+
+        let mut receiver = monitor.subscribe();
+
+        master.terminate().await.unwrap();
+
+        let state = receiver
+            .wait_for(|state| matches!(state, MasterState::PidChanged { .. }))
+            .await
+            .unwrap()
+            .clone();
+
+        let new_pid = masters.lock().unwrap()[0].id().unwrap();
+        assert_matches!(
+            state,
+            MasterState::PidChanged { old, new }
+                if old.get() == old_pid && new.get() == new_pid
+        );
+
+        drop(monitor);
+        receiver.changed().await.unwrap_err();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_master_monitor_unreachable() {
+        let path = Path::new("/tmp/openssh-mux-client-monitor-unreachable.socket");
+
+        let master = launch(path).await;
+        let monitor = MasterMonitor::builder(path)
+            .interval(Duration::from_millis(20))
+            .start()
+            .await
+            .unwrap();
+
+        let mut receiver = monitor.subscribe();
+
+        master.terminate().await.unwrap();
+
+        receiver
+            .wait_for(|state| matches!(state, MasterState::Unreachable(_)))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,514 @@
+#![forbid(unsafe_code)]
+
+use super::{Connection, EstablishedSession, Result, Session};
+
+use std::{
+    future::{poll_fn, Future},
+    io,
+    ops::{Deref, DerefMut},
+    os::unix::io::RawFd,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    task::Poll,
+    time::Duration,
+};
+
+use tokio::{
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+    task::JoinHandle,
+    time::timeout,
+};
+
+/// Builder for [`ConnectionPool`].
+#[derive(Clone, Debug)]
+pub struct ConnectionPoolBuilder {
+    control_path: PathBuf,
+    max_connections: usize,
+    min_idle: usize,
+    check_timeout: Duration,
+}
+
+impl ConnectionPoolBuilder {
+    /// * `control_path` - path to the control socket of the master.
+    pub fn new<P: Into<PathBuf>>(control_path: P) -> Self {
+        Self {
+            control_path: control_path.into(),
+            max_connections: 16,
+            min_idle: 1,
+            check_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Maximum number of connections, including idle ones and the ones
+    /// checked out, defaults to 16.
+    ///
+    /// # Panic
+    ///
+    /// If `max_connections` is 0.
+    pub fn max_connections(&mut self, max_connections: usize) -> &mut Self {
+        assert_ne!(max_connections, 0);
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Number of idle connections to keep ready in the background,
+    /// defaults to 1.
+    ///
+    /// It is capped by `max_connections`.
+    pub fn min_idle(&mut self, min_idle: usize) -> &mut Self {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// Timeout of the alive check done on checkout and of connecting
+    /// to the master, defaults to 5s.
+    pub fn check_timeout(&mut self, check_timeout: Duration) -> &mut Self {
+        self.check_timeout = check_timeout;
+        self
+    }
+
+    /// Create the pool and start refilling it in a background task.
+    ///
+    /// Must be called in the context of a tokio runtime.
+    pub fn build(&self) -> ConnectionPool {
+        let inner = Arc::new(Inner {
+            control_path: self.control_path.clone(),
+            min_idle: self.min_idle.min(self.max_connections),
+            check_timeout: self.check_timeout,
+            idle: Mutex::new(Vec::new()),
+            semaphore: Arc::new(Semaphore::new(self.max_connections)),
+            refill_notify: Notify::new(),
+            returned_notify: Notify::new(),
+        });
+
+        // Fill the pool upon start
+        inner.refill_notify.notify_one();
+
+        ConnectionPool {
+            refill_task: tokio::spawn(refill(inner.clone())),
+            inner,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    control_path: PathBuf,
+    min_idle: usize,
+    check_timeout: Duration,
+    idle: Mutex<Vec<(Connection, OwnedSemaphorePermit)>>,
+    semaphore: Arc<Semaphore>,
+    refill_notify: Notify,
+    /// Notified when a connection is returned to the pool.
+    returned_notify: Notify,
+}
+
+impl Inner {
+    fn pop_idle(&self) -> Option<(Connection, OwnedSemaphorePermit)> {
+        self.idle.lock().unwrap().pop()
+    }
+
+    fn idle_len(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    async fn connect(&self) -> Result<Connection> {
+        match timeout(self.check_timeout, Connection::connect(&self.control_path)).await {
+            Ok(res) => res,
+            Err(_elapsed) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out connecting to the ssh multiplex master",
+            )
+            .into()),
+        }
+    }
+}
+
+async fn refill(inner: Arc<Inner>) {
+    loop {
+        inner.refill_notify.notified().await;
+
+        while inner.idle_len() < inner.min_idle {
+            let permit = match inner.semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                // All connections are in use
+                Err(_) => break,
+            };
+
+            match inner.connect().await {
+                Ok(conn) => {
+                    inner.idle.lock().unwrap().push((conn, permit));
+                    // `get` might be waiting for the permit held here.
+                    inner.returned_notify.notify_one();
+                }
+                // Retry on next checkout, the error would be reported there.
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Pool of [`Connection`]s to the same ssh multiplex master that have
+/// already exchanged hello with it.
+///
+/// The background refill task is aborted on drop, connections
+/// checked out are closed instead of being returned to the pool
+/// once it is dropped.
+#[derive(Debug)]
+pub struct ConnectionPool {
+    inner: Arc<Inner>,
+    refill_task: JoinHandle<()>,
+}
+
+impl ConnectionPool {
+    /// Shortcut for `ConnectionPoolBuilder::new(control_path)`.
+    pub fn builder<P: Into<PathBuf>>(control_path: P) -> ConnectionPoolBuilder {
+        ConnectionPoolBuilder::new(control_path)
+    }
+
+    /// Path to the control socket.
+    pub fn control_path(&self) -> &Path {
+        &self.inner.control_path
+    }
+
+    /// Number of idle connections in the pool.
+    pub fn idle_connections(&self) -> usize {
+        self.inner.idle_len()
+    }
+
+    /// Check out a connection.
+    ///
+    /// Idle connections are alive checked before being returned and are
+    /// discarded if the check fails or times out.
+    ///
+    /// If there is no idle connection, a new one is created, waiting for
+    /// a connection to be returned if `max_connections` is reached.
+    pub async fn get(&self) -> Result<PooledConnection> {
+        let res = self.get_impl().await;
+        self.inner.refill_notify.notify_one();
+        res
+    }
+
+    async fn get_impl(&self) -> Result<PooledConnection> {
+        loop {
+            if let Some(conn) = self.pop_checked_idle().await {
+                return Ok(conn);
+            }
+
+            // Connections returned keep their permits, so wait for either
+            // a permit or a connection to be returned.
+            let acquire = self.inner.semaphore.clone().acquire_owned();
+            let returned = self.inner.returned_notify.notified();
+            tokio::pin!(acquire, returned);
+
+            let permit = poll_fn(|cx| match acquire.as_mut().poll(cx) {
+                Poll::Ready(permit) => Poll::Ready(Some(permit)),
+                Poll::Pending => returned.as_mut().poll(cx).map(|()| None),
+            })
+            .await;
+
+            if let Some(permit) = permit {
+                let permit = permit.expect("semaphore is never closed");
+
+                let conn = self.inner.connect().await?;
+                return Ok(self.pooled(conn, permit));
+            }
+        }
+    }
+
+    /// Pop idle connections until one passes the alive check.
+    async fn pop_checked_idle(&self) -> Option<PooledConnection> {
+        while let Some((mut conn, permit)) = self.inner.pop_idle() {
+            if let Ok(Ok(_pid)) = timeout(self.inner.check_timeout, conn.send_alive_check()).await {
+                return Some(self.pooled(conn, permit));
+            }
+        }
+        None
+    }
+
+    fn pooled(&self, conn: Connection, permit: OwnedSemaphorePermit) -> PooledConnection {
+        PooledConnection {
+            conn: Some((conn, permit)),
+            pool: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+impl Drop for ConnectionPool {
+    fn drop(&mut self) {
+        self.refill_task.abort();
+    }
+}
+
+/// A [`Connection`] checked out from [`ConnectionPool`], which is
+/// returned to the pool on drop.
+///
+/// If it is broken by an I/O error or dropped in the middle of a request,
+/// it is closed instead.
+#[derive(Debug)]
+pub struct PooledConnection {
+    /// Always `Some` until dropped or taken out by `into_inner`.
+    conn: Option<(Connection, OwnedSemaphorePermit)>,
+    pool: Weak<Inner>,
+}
+
+impl PooledConnection {
+    /// Take the connection out of the pool.
+    ///
+    /// It no longer counts towards `max_connections`.
+    pub fn into_inner(mut self) -> Connection {
+        self.conn.take().unwrap().0
+    }
+
+    /// Take the connection out of the pool and open a new session with it.
+    ///
+    /// See [`Connection::open_new_session`] for details.
+    pub async fn open_new_session(
+        self,
+        session: &Session<'_>,
+        fds: &[RawFd; 3],
+    ) -> Result<EstablishedSession> {
+        self.into_inner().open_new_session(session, fds).await
+    }
+}
+
+impl Deref for PooledConnection {
+    type Target = Connection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.conn.as_ref().unwrap().0
+    }
+}
+
+impl DerefMut for PooledConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.conn.as_mut().unwrap().0
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if let (Some(conn), Some(pool)) = (self.conn.take(), self.pool.upgrade()) {
+            if conn.0.is_reusable() {
+                pool.idle.lock().unwrap().push(conn);
+                pool.returned_notify.notify_one();
+            } else {
+                // Release the permit before refilling
+                drop(conn);
+                pool.refill_notify.notify_one();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "master")]
+    use crate::MasterBuilder;
+    use crate::{constants, Error};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{UnixListener, UnixStream},
+        time::sleep,
+    };
+
+    #[cfg(feature = "master")]
+    const STUB_SSH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../testfiles/stub_ssh");
+
+    #[cfg(feature = "master")]
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_connection_pool() {
+        let path = Path::new("/tmp/openssh-mux-client-pool.socket");
+
+        let master = MasterBuilder::new("localhost", path)
+            .ssh_binary(STUB_SSH)
+            .launch()
+            .await
+            .unwrap();
+        let pid = master.id().unwrap();
+
+        let pool = ConnectionPool::builder(path)
+            .max_connections(2)
+            .min_idle(1)
+            .build();
+
+        // Wait for the pool to be refilled in the background
+        while pool.idle_connections() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        let mut conn0 = pool.get().await.unwrap();
+        let mut conn1 = pool.get().await.unwrap();
+        assert_eq!(conn0.send_alive_check().await.unwrap().get(), pid);
+        assert_eq!(conn1.send_alive_check().await.unwrap().get(), pid);
+
+        // max_connections is reached
+        timeout(Duration::from_millis(100), pool.get())
+            .await
+            .unwrap_err();
+
+        drop(conn0);
+        assert_eq!(pool.idle_connections(), 1);
+
+        let conn2 = pool.get().await.unwrap();
+        assert_eq!(pool.idle_connections(), 0);
+
+        // Connections taken out of the pool are not counted
+        drop(conn2.into_inner());
+        let _conn3 = pool.get().await.unwrap();
+
+        // Stale idle connections are discarded
+        drop(conn1);
+        master.terminate().await.unwrap();
+        assert!(pool.get().await.is_err());
+    }
+
+    /// Accept connections, reply to hello after the delay if `hello`
+    /// is `Some` and then never reply.
+    fn hung_master(path: &Path, hello: Option<Duration>) -> JoinHandle<()> {
+        let _ = std::fs::remove_file(path);
+        let listener = UnixListener::bind(path).unwrap();
+
+        tokio::spawn(async move {
+            let mut streams: Vec<UnixStream> = Vec::new();
+            loop {
+                let (mut stream, _addr) = listener.accept().await.unwrap();
+
+                if let Some(delay) = hello {
+                    sleep(delay).await;
+
+                    let len = stream.read_u32().await.unwrap();
+                    stream.read_exact(&mut vec![0; len as usize]).await.unwrap();
+
+                    stream.write_u32(8).await.unwrap();
+                    stream.write_u32(constants::MUX_MSG_HELLO).await.unwrap();
+                    stream.write_u32(constants::SSHMUX_VER).await.unwrap();
+                }
+
+                streams.push(stream);
+            }
+        })
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_hung_idle_connection_is_discarded() {
+        let path = Path::new("/tmp/openssh-mux-client-pool-hung.socket");
+        let server = hung_master(path, Some(Duration::ZERO));
+
+        let pool = ConnectionPool::builder(path)
+            .max_connections(1)
+            .min_idle(1)
+            .check_timeout(Duration::from_millis(100))
+            .build();
+
+        while pool.idle_connections() == 0 {
+            sleep(Duration::from_millis(10)).await;
+        }
+
+        // The idle connection fails the alive check and a new one is created
+        let conn = timeout(Duration::from_secs(5), pool.get())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pool.idle_connections(), 0);
+
+        // Waiting for max_connections, the connection returned
+        // is alive checked before being handed out.
+        let waiting = pool.get();
+        tokio::pin!(waiting);
+        timeout(Duration::from_millis(50), waiting.as_mut())
+            .await
+            .unwrap_err();
+
+        drop(conn);
+        timeout(Duration::from_secs(5), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_connect_timeout() {
+        let path = Path::new("/tmp/openssh-mux-client-pool-silent.socket");
+        let server = hung_master(path, None);
+
+        let pool = ConnectionPool::builder(path)
+            .min_idle(0)
+            .check_timeout(Duration::from_millis(100))
+            .build();
+
+        let err = timeout(Duration::from_secs(5), pool.get())
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert_matches!(err, Error::IOError(err) if err.kind() == io::ErrorKind::TimedOut);
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_get_while_refilling() {
+        let path = Path::new("/tmp/openssh-mux-client-pool-refilling.socket");
+        let server = hung_master(path, Some(Duration::from_millis(50)));
+
+        let pool = ConnectionPool::builder(path)
+            .max_connections(1)
+            .min_idle(1)
+            .check_timeout(Duration::from_millis(500))
+            .build();
+
+        // Wait for the refill task to take the only permit
+        while pool.inner.semaphore.available_permits() != 0 {
+            sleep(Duration::from_millis(1)).await;
+        }
+        assert_eq!(pool.idle_connections(), 0);
+
+        // Woken up once the refill is done, the idle connection fails
+        // the alive check and a new one is created.
+        timeout(Duration::from_secs(5), pool.get())
+            .await
+            .unwrap()
+            .unwrap();
+
+        server.abort();
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unusable_connection_is_not_returned() {
+        let path = Path::new("/tmp/openssh-mux-client-pool-unusable.socket");
+        let server = hung_master(path, Some(Duration::ZERO));
+
+        let pool = ConnectionPool::builder(path)
+            .max_connections(1)
+            .min_idle(0)
+            .build();
+
+        // Dropped in the middle of a request
+        let mut conn = pool.get().await.unwrap();
+        timeout(Duration::from_millis(50), conn.send_alive_check())
+            .await
+            .unwrap_err();
+        drop(conn);
+        assert_eq!(pool.idle_connections(), 0);
+
+        // Broken by the master closing the connection
+        let mut conn = pool.get().await.unwrap();
+        server.abort();
+        let _ = server.await;
+        conn.send_alive_check().await.unwrap_err();
+        drop(conn);
+        assert_eq!(pool.idle_connections(), 0);
+
+        // The permits are released
+        assert_eq!(pool.inner.semaphore.available_permits(), 1);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
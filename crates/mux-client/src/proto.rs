@@ -0,0 +1,1170 @@
+#![forbid(unsafe_code)]
+
+//! Sans-IO implementation of the client side of the multiplex protocol.
+//!
+//! [`Protocol`] does not perform any I/O, it only encodes requests into
+//! bytes to be written, decodes bytes read from the control socket and
+//! keeps track of the requests whose responses are not received yet.
+//!
+//! [`Connection`](crate::Connection) and `blocking::Connection` (enabled by
+//! feature `blocking`) are both built on top of it, and it can be used to
+//! implement the protocol with any other I/O library.
+
+use crate::{
+    constants,
+    request::{Extensions, Fwd, Request, SessionZeroCopy},
+    Error, ErrorExt, Extension, ForwardRequest, ForwardType, Response, Result, Session,
+    SessionEvent, Socket,
+};
+
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    mem,
+    num::{NonZeroU32, Wrapping},
+};
+
+use serde::Serialize;
+use ssh_format::Serializer;
+
+/// Data to be sent to the ssh multiplex master, returned by
+/// [`Protocol::poll_transmit`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Transmit<'a> {
+    /// Write the bytes to the control socket.
+    Bytes(&'a [u8]),
+
+    /// Send the fd with the given index (`0` for stdin, `1` for stdout
+    /// and `2` for stderr) of the new session, along with a single `\0`
+    /// byte using `SCM_RIGHTS`.
+    ///
+    /// The fd must be in blocking mode.
+    Fd(usize),
+}
+
+/// Successful reply to a request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Reply {
+    /// Reply to [`Protocol::send_alive_check`] with pid of the master.
+    Alive { pid: NonZeroU32 },
+
+    /// Reply to [`Protocol::send_port_forward`],
+    /// [`Protocol::send_close_port_forward`] and
+    /// [`Protocol::send_stop_listening`].
+    Ok,
+
+    /// Reply to [`Protocol::send_dynamic_forward`] with the remote port
+    /// opened.
+    RemotePort { port: NonZeroU32 },
+
+    /// Reply to [`Protocol::send_new_session`].
+    SessionOpened { session_id: u32 },
+}
+
+/// Event decoded from the data received, returned by [`Protocol::poll_event`].
+#[derive(Debug)]
+pub enum Event {
+    /// The server replied hello with a supported protocol version.
+    ///
+    /// Its extensions are available in [`Protocol::server_extensions`].
+    Hello,
+
+    /// Response to the request with `request_id`.
+    ///
+    /// `result` is an error if the server rejected the request or
+    /// replied with something unexpected.
+    Response {
+        request_id: u32,
+        result: Result<Reply>,
+    },
+
+    /// Status change of the session opened.
+    Session {
+        session_id: u32,
+        event: SessionEvent,
+    },
+}
+
+#[derive(Debug)]
+enum Chunk {
+    Bytes { data: Vec<u8>, written: usize },
+    Fd(usize),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Expect {
+    Alive,
+    Ok,
+    RemotePort,
+    SessionOpened,
+}
+
+/// Sans-IO state machine of a connection to the ssh multiplex master.
+///
+/// The hello message is queued on creation. The typical usage is:
+///  - queue a request with one of the `send_*` methods;
+///  - write everything returned by [`Protocol::poll_transmit`] and
+///    call [`Protocol::advance_transmit`] after each write;
+///  - read at least [`Protocol::bytes_needed`] bytes, feed them to
+///    [`Protocol::handle_input`] and call [`Protocol::poll_event`]
+///    until the event is returned.
+///
+/// Every request queued gets a new request id and is sent, unless
+/// resuming is enabled with [`Protocol::set_resume_identical`].
+#[derive(Debug)]
+pub struct Protocol {
+    serializer: Serializer,
+    request_id: Wrapping<u32>,
+    transmits: VecDeque<Chunk>,
+    /// Requests whose responses are not received yet, in order.
+    pending: VecDeque<(u32, Expect)>,
+    /// Whether [`Protocol::set_resume_identical`] is enabled.
+    resume_identical: bool,
+    /// The last request sent if its response is not received yet,
+    /// only recorded if `resume_identical` is enabled.
+    last_request: Option<(u32, Vec<u8>)>,
+    input: Vec<u8>,
+    hello_received: bool,
+    server_extensions: Vec<Extension>,
+    session_id: Option<u32>,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Protocol {
+    /// Create a new `Protocol` with hello queued.
+    pub fn new() -> Self {
+        Self::with_extensions(&[])
+    }
+
+    /// Create a new `Protocol` with hello queued, which carries
+    /// `extensions` as `(name, value)` pairs.
+    ///
+    /// The server ignores extensions it does not recognise.
+    pub fn with_extensions(extensions: &[(&str, &str)]) -> Self {
+        let mut protocol = Self {
+            // All request packets are at least 12 bytes large,
+            // and variant [`Request::NewSession`] takes 36 bytes to
+            // serialize.
+            serializer: Serializer::new(Vec::with_capacity(36)),
+            request_id: Wrapping(0),
+            transmits: VecDeque::new(),
+            pending: VecDeque::new(),
+            resume_identical: false,
+            last_request: None,
+            // All reponse packets are at least 16 bytes large.
+            input: Vec::with_capacity(32),
+            hello_received: false,
+            server_extensions: Vec::new(),
+            session_id: None,
+        };
+
+        let hello = Request::Hello {
+            version: constants::SSHMUX_VER,
+        };
+        Self::serialize(&mut protocol.serializer, &(hello, Extensions(extensions)))
+            .expect("Serializing hello never fails");
+        let data = mem::take(&mut protocol.serializer.output);
+        protocol
+            .transmits
+            .push_back(Chunk::Bytes { data, written: 0 });
+
+        protocol
+    }
+
+    /// Enable or disable resuming identical requests, which is disabled
+    /// by default.
+    ///
+    /// Once enabled, if a request is queued while the last request with
+    /// identical content is still waiting for its response, the new
+    /// request is not sent and the id of the last request is returned
+    /// instead.
+    ///
+    /// This makes retrying a request after the I/O is cancelled or
+    /// interrupted safe, since all the state of the in-flight request is
+    /// kept here. [`Protocol::send_forwards`] never resumes.
+    pub fn set_resume_identical(&mut self, enable: bool) {
+        self.resume_identical = enable;
+        if !enable {
+            self.last_request = None;
+        }
+    }
+
+    /// Serialize `value` along with its header into `serializer.output`.
+    fn serialize<T: Serialize>(serializer: &mut Serializer, value: &T) -> Result<()> {
+        serializer.reset_counter();
+        serializer.output.clear();
+        // Reserve the header
+        serializer.output.resize(4, 0);
+
+        value.serialize(&mut *serializer)?;
+
+        let header = serializer.create_header(0)?;
+        // Write the header
+        serializer.output[..4].copy_from_slice(&header);
+
+        Ok(())
+    }
+
+    /// Queue the request created by `create_request` and return its
+    /// request id.
+    fn queue_request<T, F>(&mut self, expect: Expect, create_request: F) -> Result<u32>
+    where
+        T: Serialize,
+        F: Fn(u32) -> T,
+    {
+        if let Some((request_id, last_request)) = &self.last_request {
+            let request_id = *request_id;
+
+            Self::serialize(&mut self.serializer, &create_request(request_id))?;
+            if &self.serializer.output == last_request {
+                return Ok(request_id);
+            }
+        }
+
+        let request_id = self.request_id.0;
+        self.request_id += Wrapping(1);
+
+        Self::serialize(&mut self.serializer, &create_request(request_id))?;
+
+        let data = self.serializer.output.clone();
+        self.transmits.push_back(Chunk::Bytes { data, written: 0 });
+        self.pending.push_back((request_id, expect));
+        if self.resume_identical {
+            self.last_request = Some((request_id, mem::take(&mut self.serializer.output)));
+        }
+
+        Ok(request_id)
+    }
+
+    /// Queue an alive check, the server replies with [`Reply::Alive`].
+    pub fn send_alive_check(&mut self) -> Result<u32> {
+        self.queue_request(Expect::Alive, |request_id| Request::AliveCheck {
+            request_id,
+        })
+    }
+
+    /// Queue a request for opening a new session, followed by the
+    /// fds of stdin, stdout and stderr.
+    ///
+    /// The server replies with [`Reply::SessionOpened`], after which
+    /// only [`Event::Session`] would be received.
+    pub fn send_new_session(&mut self, session: &Session<'_>) -> Result<u32> {
+        let term = session.term.as_ref();
+        let cmd = session.cmd.as_ref();
+
+        let last_request_id = self
+            .last_request
+            .as_ref()
+            .map(|(request_id, _)| *request_id);
+
+        let request_id = self.queue_request(Expect::SessionOpened, |request_id| {
+            let request = Request::NewSession {
+                request_id,
+                session: SessionZeroCopy {
+                    tty: session.tty,
+                    x11_forwarding: session.x11_forwarding,
+                    agent: session.agent,
+                    subsystem: session.subsystem,
+                    escape_ch: session.escape_ch,
+                },
+            };
+
+            (request, term, cmd)
+        })?;
+
+        if last_request_id != Some(request_id) {
+            self.transmits.extend((0..3).map(Chunk::Fd));
+        }
+
+        Ok(request_id)
+    }
+
+    fn send_fwd(&mut self, fwd: &Fwd<'_>, close: bool, expect: Expect) -> Result<u32> {
+        let (fwd_mode, listen_socket, connect_socket) = fwd.as_serializable();
+
+        self.queue_request(expect, |request_id| {
+            let request = if close {
+                Request::CloseFwd {
+                    request_id,
+                    fwd_mode,
+                }
+            } else {
+                Request::OpenFwd {
+                    request_id,
+                    fwd_mode,
+                }
+            };
+
+            (request, listen_socket, &connect_socket)
+        })
+    }
+
+    /// Queue a request for local/remote port forwarding,
+    /// the server replies with [`Reply::Ok`].
+    pub fn send_port_forward(
+        &mut self,
+        forward_type: ForwardType,
+        listen_socket: &Socket<'_>,
+        connect_socket: &Socket<'_>,
+    ) -> Result<u32> {
+        let fwd = Fwd::new(forward_type, listen_socket, connect_socket);
+        self.send_fwd(&fwd, false, Expect::Ok)
+    }
+
+    /// Queue a request for local/remote port forwarding closure,
+    /// the server replies with [`Reply::Ok`].
+    pub fn send_close_port_forward(
+        &mut self,
+        forward_type: ForwardType,
+        listen_socket: &Socket<'_>,
+        connect_socket: &Socket<'_>,
+    ) -> Result<u32> {
+        let fwd = Fwd::new(forward_type, listen_socket, connect_socket);
+        self.send_fwd(&fwd, true, Expect::Ok)
+    }
+
+    /// Queue all `forwards` and return their request ids in the same order,
+    /// the server replies each of them with [`Reply::Ok`].
+    ///
+    /// Unlike other requests, they are never resumed even if
+    /// [`Protocol::set_resume_identical`] is enabled.
+    pub fn send_forwards(&mut self, forwards: &[ForwardRequest<'_>]) -> Result<Vec<u32>> {
+        forwards
+            .iter()
+            .map(|forward| {
+                self.last_request = None;
+
+                match *forward {
+                    ForwardRequest::Open {
+                        forward_type,
+                        listen_socket,
+                        connect_socket,
+                    } => self.send_port_forward(forward_type, listen_socket, connect_socket),
+                    ForwardRequest::Close {
+                        forward_type,
+                        listen_socket,
+                        connect_socket,
+                    } => self.send_close_port_forward(forward_type, listen_socket, connect_socket),
+                }
+            })
+            .collect()
+    }
+
+    /// Queue a request for dynamic forwarding,
+    /// the server replies with [`Reply::RemotePort`].
+    pub fn send_dynamic_forward(&mut self, listen_socket: &Socket<'_>) -> Result<u32> {
+        let fwd = Fwd::Dynamic { listen_socket };
+        self.send_fwd(&fwd, false, Expect::RemotePort)
+    }
+
+    /// Queue a request for the master to stop accepting new multiplexing
+    /// requests, the server replies with [`Reply::Ok`].
+    pub fn send_stop_listening(&mut self) -> Result<u32> {
+        self.queue_request(Expect::Ok, |request_id| Request::StopListening {
+            request_id,
+        })
+    }
+
+    /// Queue a request for the master to terminate, the server replies
+    /// with [`Reply::Ok`] unless it exits before sending the reply.
+    pub fn send_terminate(&mut self) -> Result<u32> {
+        self.queue_request(Expect::Ok, |request_id| Request::Terminate { request_id })
+    }
+
+    /// Return the next data to be sent or `None` if there is nothing to send.
+    pub fn poll_transmit(&self) -> Option<Transmit<'_>> {
+        self.transmits.front().map(|chunk| match chunk {
+            Chunk::Bytes { data, written } => Transmit::Bytes(&data[*written..]),
+            Chunk::Fd(index) => Transmit::Fd(*index),
+        })
+    }
+
+    /// Mark `n` bytes returned by [`Protocol::poll_transmit`] as sent.
+    ///
+    /// For [`Transmit::Fd`], `n` must be `1`.
+    pub fn advance_transmit(&mut self, n: usize) {
+        let done = match self.transmits.front_mut() {
+            Some(Chunk::Bytes { data, written }) => {
+                *written += n;
+                debug_assert!(*written <= data.len());
+                *written >= data.len()
+            }
+            Some(Chunk::Fd(_)) => {
+                debug_assert_eq!(n, 1);
+                true
+            }
+            None => {
+                debug_assert_eq!(n, 0);
+                false
+            }
+        };
+
+        if done {
+            self.transmits.pop_front();
+        }
+    }
+
+    /// Extensions `(name, value)` sent by the server in its hello message.
+    ///
+    /// It is empty until [`Event::Hello`] is returned.
+    pub fn server_extensions(&self) -> &[Extension] {
+        &self.server_extensions
+    }
+
+    /// Return `true` if there are requests whose responses are not
+    /// received yet.
+    pub fn has_pending_requests(&self) -> bool {
+        !self.hello_received || !self.pending.is_empty()
+    }
+
+    /// Return `true` if hello is received and there is nothing left to
+    /// send or receive.
+    #[cfg(feature = "pool")]
+    pub(crate) fn is_idle(&self) -> bool {
+        self.hello_received
+            && self.pending.is_empty()
+            && self.transmits.is_empty()
+            && self.input.is_empty()
+    }
+
+    /// Feed data read from the control socket.
+    pub fn handle_input(&mut self, data: &[u8]) {
+        self.input.extend_from_slice(data);
+    }
+
+    /// Return the minimum number of bytes to read before the next event
+    /// can be decoded, `0` if [`Protocol::poll_event`] would return an event.
+    pub fn bytes_needed(&self) -> usize {
+        match self.packet_len() {
+            Some(packet_len) => (4 + packet_len).saturating_sub(self.input.len()),
+            None => 4 - self.input.len(),
+        }
+    }
+
+    fn packet_len(&self) -> Option<usize> {
+        let header: [u8; 4] = self.input.get(..4)?.try_into().unwrap();
+        Some(u32::from_be_bytes(header).try_into().unwrap())
+    }
+
+    /// Decode the next event from the data fed, return `None` if more data
+    /// is required.
+    ///
+    /// Return an error if the data received cannot be decoded.
+    ///
+    /// A message of type unknown to this crate is reported as
+    /// [`Error::UnsupportedResponse`], either in the [`Event::Response`]
+    /// of the request it responds to or returned directly.
+    /// Either way, the message is consumed and the connection stays usable.
+    pub fn poll_event(&mut self) -> Result<Option<Event>> {
+        let packet_len = match self.packet_len() {
+            Some(packet_len) if self.input.len() >= 4 + packet_len => packet_len,
+            _ => return Ok(None),
+        };
+
+        let response = Response::from_packet(&self.input[4..(4 + packet_len)]);
+
+        // Remove the packet from buffer
+        self.input.drain(..(4 + packet_len));
+
+        self.handle_response(response?).map(Some)
+    }
+
+    fn handle_response(&mut self, response: Response) -> Result<Event> {
+        if !self.hello_received {
+            self.server_extensions = check_hello(response)?;
+            self.hello_received = true;
+            return Ok(Event::Hello);
+        }
+
+        if let Response::TtyAllocFail { session_id } | Response::ExitMessage { session_id, .. } =
+            response
+        {
+            let expected_session_id = self
+                .session_id
+                .ok_or_else(|| Error::invalid_server_response(&"Response to request", &response))?;
+
+            return Ok(Event::Session {
+                session_id,
+                event: check_session_event(expected_session_id, response)?,
+            });
+        }
+
+        // The master may reply out of order, e.g. remote forwarding
+        // requests are only replied once the server responds.
+        let pending = response
+            .response_id()
+            .and_then(|response_id| self.take_pending(response_id));
+
+        if let Response::Unknown { msg_type, .. } = &response {
+            let error = Error::UnsupportedResponse(*msg_type);
+
+            return match pending {
+                Some((request_id, _)) => Ok(Event::Response {
+                    request_id,
+                    result: Err(error),
+                }),
+                // The packet has been consumed, so the caller can still
+                // continue to poll events after this error.
+                None => Err(error),
+            };
+        }
+
+        let (request_id, expect) = match pending {
+            Some(pending) => pending,
+            None if self.pending.is_empty() => {
+                return Err(Error::invalid_server_response(
+                    &"no response as no request is sent",
+                    &response,
+                ))
+            }
+            None if response.response_id().is_some() => return Err(Error::UnmatchedRequestId),
+            None => {
+                return Err(Error::invalid_server_response(
+                    &"Response to request",
+                    &response,
+                ))
+            }
+        };
+
+        let result = match expect {
+            Expect::Alive => check_alive(request_id, response).map(|pid| Reply::Alive { pid }),
+            Expect::Ok => check_ok(request_id, response).map(|()| Reply::Ok),
+            Expect::RemotePort => {
+                check_remote_port(request_id, response).map(|port| Reply::RemotePort { port })
+            }
+            Expect::SessionOpened => check_session_opened(request_id, response).map(|session_id| {
+                self.session_id = Some(session_id);
+                Reply::SessionOpened { session_id }
+            }),
+        };
+
+        Ok(Event::Response { request_id, result })
+    }
+
+    /// Remove the pending request with `request_id` and return it.
+    fn take_pending(&mut self, request_id: u32) -> Option<(u32, Expect)> {
+        let index = self.pending.iter().position(|(id, _)| *id == request_id)?;
+        let (request_id, expect) = self.pending.remove(index)?;
+
+        if matches!(&self.last_request, Some((id, _)) if *id == request_id) {
+            self.last_request = None;
+        }
+
+        Some((request_id, expect))
+    }
+}
+
+/// Results of requests queued by [`Protocol::send_forwards`], matched by
+/// request id.
+#[derive(Debug)]
+pub(crate) struct BatchResults {
+    request_ids: Vec<u32>,
+    results: Vec<Option<Result<()>>>,
+    remaining: usize,
+}
+
+impl BatchResults {
+    pub(crate) fn new(request_ids: Vec<u32>) -> Self {
+        Self {
+            results: request_ids.iter().map(|_| None).collect(),
+            remaining: request_ids.len(),
+            request_ids,
+        }
+    }
+
+    pub(crate) fn is_done(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Record the result in `event` if it is a response to one of the
+    /// requests, otherwise discard it.
+    pub(crate) fn handle_event(&mut self, event: Event) -> Result<()> {
+        match event {
+            Event::Response { request_id, result } => {
+                // Request ids are never reused within a batch since the
+                // requests are not coalesced.
+                if let Some(index) = self.request_ids.iter().position(|id| *id == request_id) {
+                    self.results[index] = Some(result.map(|reply| match reply {
+                        Reply::Ok => (),
+                        reply => unreachable!("Unexpected reply {:?}", reply),
+                    }));
+                    self.remaining -= 1;
+                }
+
+                Ok(())
+            }
+            event => Err(Error::invalid_server_response(
+                &"Response to request",
+                &event,
+            )),
+        }
+    }
+
+    pub(crate) fn into_results(self) -> Vec<Result<()>> {
+        self.results
+            .into_iter()
+            .map(|result| result.expect("All responses are received"))
+            .collect()
+    }
+}
+
+fn check_response_id(request_id: u32, response_id: u32) -> Result<()> {
+    if request_id != response_id {
+        Err(Error::UnmatchedRequestId)
+    } else {
+        Ok(())
+    }
+}
+
+/// Convert `response` that is not the expected one into an error.
+fn unexpected(request_id: u32, response: Response, expected: &'static &'static str) -> Error {
+    match response {
+        Response::PermissionDenied {
+            response_id,
+            reason,
+        } => check_response_id(request_id, response_id)
+            .err()
+            .unwrap_or(Error::PermissionDenied(reason)),
+        Response::Failure {
+            response_id,
+            reason,
+        } => check_response_id(request_id, response_id)
+            .err()
+            .unwrap_or(Error::RequestFailure(reason)),
+        response => Error::invalid_server_response(expected, &response),
+    }
+}
+
+/// Return extensions of the server.
+fn check_hello(response: Response) -> Result<Vec<Extension>> {
+    if let Response::Hello {
+        version,
+        extensions,
+    } = response
+    {
+        if version != constants::SSHMUX_VER {
+            Err(Error::UnsupportedMuxProtocol)
+        } else {
+            Ok(extensions)
+        }
+    } else if let Response::Unknown { msg_type, .. } = response {
+        Err(Error::UnsupportedResponse(msg_type))
+    } else {
+        Err(Error::invalid_server_response(&"Hello message", &response))
+    }
+}
+
+/// Return pid of the ssh mux server.
+fn check_alive(request_id: u32, response: Response) -> Result<NonZeroU32> {
+    if let Response::Alive {
+        response_id,
+        server_pid,
+    } = response
+    {
+        check_response_id(request_id, response_id)?;
+        NonZeroU32::new(server_pid).ok_or(Error::InvalidPid)
+    } else {
+        Err(Error::invalid_server_response(
+            &"Response::Alive",
+            &response,
+        ))
+    }
+}
+
+fn check_ok(request_id: u32, response: Response) -> Result<()> {
+    match response {
+        Response::Ok { response_id } => check_response_id(request_id, response_id),
+        response => Err(unexpected(
+            request_id,
+            response,
+            &"Ok, PermissionDenied or Failure",
+        )),
+    }
+}
+
+/// Return session_id.
+fn check_session_opened(request_id: u32, response: Response) -> Result<u32> {
+    match response {
+        Response::SessionOpened {
+            response_id,
+            session_id,
+        } => {
+            check_response_id(request_id, response_id)?;
+            Ok(session_id)
+        }
+        response => Err(unexpected(
+            request_id,
+            response,
+            &"SessionOpened, PermissionDenied or Failure",
+        )),
+    }
+}
+
+/// Return remote port opened for dynamic forwarding.
+fn check_remote_port(request_id: u32, response: Response) -> Result<NonZeroU32> {
+    match response {
+        Response::RemotePort {
+            response_id,
+            remote_port,
+        } => {
+            check_response_id(request_id, response_id)?;
+            NonZeroU32::new(remote_port).ok_or(Error::InvalidPort)
+        }
+        response => Err(unexpected(
+            request_id,
+            response,
+            &"RemotePort, PermissionDenied or Failure",
+        )),
+    }
+}
+
+fn check_session_event(session_id: u32, response: Response) -> Result<SessionEvent> {
+    let check_session_id = |id| {
+        if session_id != id {
+            Err(Error::UnmatchedSessionId)
+        } else {
+            Ok(())
+        }
+    };
+
+    match response {
+        Response::TtyAllocFail { session_id } => {
+            check_session_id(session_id)?;
+            Ok(SessionEvent::TtyAllocFail)
+        }
+        Response::ExitMessage {
+            session_id,
+            exit_value,
+        } => {
+            check_session_id(session_id)?;
+            Ok(SessionEvent::Exited {
+                exit_value: Some(exit_value),
+            })
+        }
+        response => Err(Error::invalid_server_response(
+            &"TtyAllocFail or ExitMessage",
+            &response,
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::borrow::Cow;
+
+    fn packet(fields: &[u32]) -> Vec<u8> {
+        let mut packet = ((fields.len() * 4) as u32).to_be_bytes().to_vec();
+        for field in fields {
+            packet.extend_from_slice(&field.to_be_bytes());
+        }
+        packet
+    }
+
+    /// Return all bytes transmitted and the fds sent.
+    fn transmit_all(protocol: &mut Protocol) -> (Vec<u8>, Vec<usize>) {
+        let mut bytes = Vec::new();
+        let mut fds = Vec::new();
+
+        while let Some(transmit) = protocol.poll_transmit() {
+            match transmit {
+                Transmit::Bytes(data) => {
+                    // Simulate short writes
+                    let n = data.len().min(5);
+                    bytes.extend_from_slice(&data[..n]);
+                    protocol.advance_transmit(n);
+                }
+                Transmit::Fd(index) => {
+                    fds.push(index);
+                    protocol.advance_transmit(1);
+                }
+            }
+        }
+
+        (bytes, fds)
+    }
+
+    fn connected() -> Protocol {
+        let mut protocol = Protocol::new();
+
+        let (bytes, fds) = transmit_all(&mut protocol);
+        assert_eq!(
+            bytes,
+            packet(&[constants::MUX_MSG_HELLO, constants::SSHMUX_VER])
+        );
+        assert!(fds.is_empty());
+
+        protocol.handle_input(&packet(&[constants::MUX_MSG_HELLO, constants::SSHMUX_VER]));
+        assert_matches!(protocol.poll_event(), Ok(Some(Event::Hello)));
+
+        protocol
+    }
+
+    #[test]
+    fn test_extensions() {
+        fn string(s: &[u8]) -> Vec<u8> {
+            let mut bytes = (s.len() as u32).to_be_bytes().to_vec();
+            bytes.extend_from_slice(s);
+            bytes
+        }
+
+        fn hello(extensions: &[(&[u8], &[u8])]) -> Vec<u8> {
+            let mut body = packet(&[constants::MUX_MSG_HELLO, constants::SSHMUX_VER]).split_off(4);
+            for (name, value) in extensions {
+                body.extend(string(name));
+                body.extend(string(value));
+            }
+
+            let mut packet = (body.len() as u32).to_be_bytes().to_vec();
+            packet.extend(body);
+            packet
+        }
+
+        let mut protocol = Protocol::with_extensions(&[("a@example.com", "1")]);
+
+        let (bytes, _fds) = transmit_all(&mut protocol);
+        assert_eq!(bytes, hello(&[(b"a@example.com", b"1")]));
+
+        // Values are not required to be UTF-8
+        protocol.handle_input(&hello(&[
+            (b"b@example.com", b""),
+            (b"c@example.com", b"\xff"),
+        ]));
+        assert!(protocol.server_extensions().is_empty());
+        assert_matches!(protocol.poll_event(), Ok(Some(Event::Hello)));
+        assert_matches!(
+            protocol.server_extensions(),
+            [(name0, value0), (name1, value1)]
+                if &**name0 == b"b@example.com"
+                    && value0.is_empty()
+                    && &**name1 == b"c@example.com"
+                    && &**value1 == b"\xff"
+        );
+    }
+
+    #[test]
+    fn test_alive_check() {
+        let mut protocol = connected();
+
+        let request_id = protocol.send_alive_check().unwrap();
+        let (bytes, _fds) = transmit_all(&mut protocol);
+        assert_eq!(bytes, packet(&[constants::MUX_C_ALIVE_CHECK, request_id]));
+
+        // Feed the response byte by byte
+        let response = packet(&[constants::MUX_S_ALIVE, request_id, 1234]);
+        for byte in &response {
+            assert!(protocol.bytes_needed() > 0);
+            assert_matches!(protocol.poll_event(), Ok(None));
+            protocol.handle_input(&[*byte]);
+        }
+        assert_eq!(protocol.bytes_needed(), 0);
+
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response { request_id: id, result: Ok(Reply::Alive { pid }) }))
+                if id == request_id && pid.get() == 1234
+        );
+        assert!(!protocol.has_pending_requests());
+    }
+
+    #[test]
+    fn test_terminate() {
+        let mut protocol = connected();
+
+        let request_id = protocol.send_terminate().unwrap();
+        let (bytes, _fds) = transmit_all(&mut protocol);
+        assert_eq!(bytes, packet(&[constants::MUX_C_TERMINATE, request_id]));
+
+        protocol.handle_input(&packet(&[constants::MUX_S_OK, request_id]));
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response { request_id: id, result: Ok(Reply::Ok) })) if id == request_id
+        );
+    }
+
+    #[test]
+    fn test_distinct_requests() {
+        let mut protocol = connected();
+
+        let first_id = protocol.send_alive_check().unwrap();
+        let second_id = protocol.send_alive_check().unwrap();
+        assert_ne!(first_id, second_id);
+
+        let (bytes, _fds) = transmit_all(&mut protocol);
+        assert_eq!(
+            bytes,
+            [
+                packet(&[constants::MUX_C_ALIVE_CHECK, first_id]),
+                packet(&[constants::MUX_C_ALIVE_CHECK, second_id]),
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_retry() {
+        let mut protocol = connected();
+        protocol.set_resume_identical(true);
+
+        let request_id = protocol.send_alive_check().unwrap();
+        assert_eq!(protocol.send_alive_check().unwrap(), request_id);
+
+        let (bytes, _fds) = transmit_all(&mut protocol);
+        assert_eq!(bytes, packet(&[constants::MUX_C_ALIVE_CHECK, request_id]));
+
+        // A different request is sent after the pending one.
+        let stop_listening_id = protocol.send_stop_listening().unwrap();
+        assert_ne!(stop_listening_id, request_id);
+
+        protocol.handle_input(&packet(&[constants::MUX_S_ALIVE, request_id, 1234]));
+        protocol.handle_input(&packet(&[constants::MUX_S_OK, stop_listening_id]));
+
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response { request_id: id, .. })) if id == request_id
+        );
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response { request_id: id, result: Ok(Reply::Ok) }))
+                if id == stop_listening_id
+        );
+    }
+
+    #[test]
+    fn test_new_session() {
+        let mut protocol = connected();
+
+        let session = Session::builder()
+            .term(Cow::Borrowed("xterm".try_into().unwrap()))
+            .cmd(Cow::Borrowed("true".try_into().unwrap()))
+            .build();
+
+        let request_id = protocol.send_new_session(&session).unwrap();
+        let (bytes, fds) = transmit_all(&mut protocol);
+        assert_eq!(
+            &bytes[4..12],
+            &packet(&[constants::MUX_C_NEW_SESSION, request_id])[4..]
+        );
+        assert_eq!(fds, [0, 1, 2]);
+
+        protocol.handle_input(&packet(&[constants::MUX_S_SESSION_OPENED, request_id, 7]));
+        protocol.handle_input(&packet(&[constants::MUX_S_TTY_ALLOC_FAIL, 7]));
+        protocol.handle_input(&packet(&[constants::MUX_S_EXIT_MESSAGE, 7, 3]));
+
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                result: Ok(Reply::SessionOpened { session_id: 7 }),
+                ..
+            }))
+        );
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Session {
+                session_id: 7,
+                event: SessionEvent::TtyAllocFail
+            }))
+        );
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Session {
+                session_id: 7,
+                event: SessionEvent::Exited {
+                    exit_value: Some(3)
+                }
+            }))
+        );
+    }
+
+    #[test]
+    fn test_rejected() {
+        let mut protocol = connected();
+
+        let socket = Socket::TcpSocket {
+            port: 1234,
+            host: "127.0.0.1".into(),
+        };
+        let request_id = protocol
+            .send_port_forward(ForwardType::Local, &socket, &socket)
+            .unwrap();
+        transmit_all(&mut protocol);
+
+        let mut response = packet(&[constants::MUX_S_PERMISSION_DENIED, request_id, 4]);
+        response[3] += 4;
+        response.extend_from_slice(b"nope");
+        protocol.handle_input(&response);
+
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                result: Err(Error::PermissionDenied(reason)),
+                ..
+            })) if &*reason == "nope"
+        );
+    }
+
+    #[test]
+    fn test_out_of_order() {
+        let mut protocol = connected();
+
+        let socket = Socket::TcpSocket {
+            port: 1234,
+            host: "127.0.0.1".into(),
+        };
+        let request_ids = protocol
+            .send_forwards(&[
+                ForwardRequest::Open {
+                    forward_type: ForwardType::Remote,
+                    listen_socket: &socket,
+                    connect_socket: &socket,
+                },
+                ForwardRequest::Open {
+                    forward_type: ForwardType::Local,
+                    listen_socket: &socket,
+                    connect_socket: &socket,
+                },
+            ])
+            .unwrap();
+        transmit_all(&mut protocol);
+
+        let mut results = BatchResults::new(request_ids.clone());
+
+        // The local forward is replied first, then the remote one is
+        // rejected.
+        let mut response = packet(&[constants::MUX_S_FAILURE, request_ids[0], 4]);
+        response[3] += 4;
+        response.extend_from_slice(b"nope");
+        protocol.handle_input(&packet(&[constants::MUX_S_OK, request_ids[1]]));
+        protocol.handle_input(&response);
+
+        let event = protocol.poll_event().unwrap().unwrap();
+        assert_matches!(
+            &event,
+            Event::Response {
+                request_id,
+                result: Ok(Reply::Ok),
+            } if *request_id == request_ids[1]
+        );
+        results.handle_event(event).unwrap();
+
+        let event = protocol.poll_event().unwrap().unwrap();
+        assert_matches!(
+            &event,
+            Event::Response {
+                request_id,
+                result: Err(Error::RequestFailure(_)),
+            } if *request_id == request_ids[0]
+        );
+        results.handle_event(event).unwrap();
+
+        assert!(results.is_done());
+        assert!(!protocol.has_pending_requests());
+
+        let results = results.into_results();
+        assert_matches!(results[0], Err(Error::RequestFailure(ref reason)) if &**reason == "nope");
+        assert_matches!(results[1], Ok(()));
+
+        // Unknown replies are matched by id as well
+        const MUX_S_PROXY: u32 = 0x8000000F;
+
+        let first_id = protocol.send_alive_check().unwrap();
+        let second_id = protocol.send_alive_check().unwrap();
+        transmit_all(&mut protocol);
+
+        protocol.handle_input(&packet(&[MUX_S_PROXY, second_id]));
+        protocol.handle_input(&packet(&[constants::MUX_S_ALIVE, first_id, 1234]));
+
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                request_id,
+                result: Err(Error::UnsupportedResponse(MUX_S_PROXY)),
+            })) if request_id == second_id
+        );
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                request_id,
+                result: Ok(Reply::Alive { .. }),
+            })) if request_id == first_id
+        );
+    }
+
+    #[test]
+    fn test_unmatched() {
+        let mut protocol = connected();
+
+        let request_id = protocol.send_alive_check().unwrap();
+        transmit_all(&mut protocol);
+
+        protocol.handle_input(&packet(&[constants::MUX_S_OK, request_id + 1]));
+        protocol.handle_input(&packet(&[constants::MUX_S_ALIVE, request_id, 1234]));
+
+        assert_matches!(protocol.poll_event(), Err(Error::UnmatchedRequestId));
+        // The request is still pending
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                request_id: id,
+                result: Ok(Reply::Alive { .. }),
+            })) if id == request_id
+        );
+    }
+
+    #[test]
+    fn test_unknown() {
+        const MUX_S_PROXY: u32 = 0x8000000F;
+
+        let mut protocol = connected();
+
+        let request_id = protocol.send_alive_check().unwrap();
+        transmit_all(&mut protocol);
+
+        // Unsolicited message
+        protocol.handle_input(&packet(&[0x1234]));
+        // Response to the request
+        protocol.handle_input(&packet(&[MUX_S_PROXY, request_id, 0]));
+
+        assert_matches!(
+            protocol.poll_event(),
+            Err(Error::UnsupportedResponse(0x1234))
+        );
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                request_id: id,
+                result: Err(Error::UnsupportedResponse(MUX_S_PROXY)),
+            })) if id == request_id
+        );
+        assert!(!protocol.has_pending_requests());
+
+        // The stream stays in sync
+        let request_id = protocol.send_alive_check().unwrap();
+        assert_matches!(protocol.poll_event(), Ok(None));
+        protocol.handle_input(&packet(&[constants::MUX_S_ALIVE, request_id, 1234]));
+        assert_matches!(
+            protocol.poll_event(),
+            Ok(Some(Event::Response {
+                result: Ok(Reply::Alive { .. }),
+                ..
+            }))
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut protocol = Protocol::new();
+        protocol.handle_input(&packet(&[constants::MUX_MSG_HELLO, 3]));
+        assert_matches!(protocol.poll_event(), Err(Error::UnsupportedMuxProtocol));
+
+        let mut protocol = connected();
+        protocol.handle_input(&packet(&[constants::MUX_S_OK, 0]));
+        assert_matches!(protocol.poll_event(), Err(Error::InvalidServerResponse(..)));
+    }
+}
@@ -1,11 +1,12 @@
 #![forbid(unsafe_code)]
 
-use super::{constants, default_config, utils::MaybeOwned, NonZeroByteSlice, NonZeroByteVec};
+use super::{
+    constants, default_config, utils::MaybeOwned, ForwardType, NonZeroByteSlice, NonZeroByteVec,
+};
 
-use std::{borrow::Cow, path::Path};
+use std::{borrow::Cow, os::unix::ffi::OsStrExt, path::Path};
 
-use cfg_if::cfg_if;
-use serde::{Serialize, Serializer};
+use serde::{ser::SerializeTuple, Serialize, Serializer};
 use typed_builder::TypedBuilder;
 
 #[derive(Copy, Clone, Debug)]
@@ -57,6 +58,13 @@ pub(crate) enum Request {
     /// A server may reply with `Response::Ok`, `Response::PermissionDenied` or
     /// `Response::Failure`.
     StopListening { request_id: u32 },
+
+    /// A client may request the master to terminate immediately.
+    ///
+    /// A server may reply with `Response::Ok` or
+    /// `Response::PermissionDenied`, though the connection might be
+    /// closed before the reply is sent.
+    Terminate { request_id: u32 },
 }
 impl Serialize for Request {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
@@ -106,7 +114,26 @@ impl Serialize for Request {
                 "StopListening",
                 request_id,
             ),
+            Terminate { request_id } => serializer.serialize_newtype_variant(
+                "Request",
+                MUX_C_TERMINATE,
+                "Terminate",
+                request_id,
+            ),
+        }
+    }
+}
+
+/// Extensions `(name, value)` following the version in `Request::Hello`.
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct Extensions<'a>(pub(crate) &'a [(&'a str, &'a str)]);
+impl Serialize for Extensions<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut tuple = serializer.serialize_tuple(self.0.len())?;
+        for extension in self.0 {
+            tuple.serialize_element(extension)?;
         }
+        tuple.end()
     }
 }
 
@@ -164,6 +191,23 @@ pub enum Fwd<'a> {
     },
 }
 impl<'a> Fwd<'a> {
+    pub(crate) fn new(
+        forward_type: ForwardType,
+        listen_socket: &'a Socket<'a>,
+        connect_socket: &'a Socket<'a>,
+    ) -> Self {
+        match forward_type {
+            ForwardType::Local => Fwd::Local {
+                listen_socket,
+                connect_socket,
+            },
+            ForwardType::Remote => Fwd::Remote {
+                listen_socket,
+                connect_socket,
+            },
+        }
+    }
+
     pub(crate) fn as_serializable(&self) -> (u32, &'a Socket<'a>, MaybeOwned<'a, Socket<'a>>) {
         use Fwd::*;
 
@@ -204,8 +248,6 @@ trait PathExt {
     fn to_non_null_bytes(&self) -> Cow<'_, NonZeroByteSlice>;
 
     fn to_bytes(&self) -> Cow<'_, [u8]>;
-
-    fn to_string_lossy_and_as_bytes(&self) -> Cow<'_, [u8]>;
 }
 
 impl PathExt for Path {
@@ -217,22 +259,7 @@ impl PathExt for Path {
     }
 
     fn to_bytes(&self) -> Cow<'_, [u8]> {
-        cfg_if! {
-            if #[cfg(unix)] {
-                use std::os::unix::ffi::OsStrExt;
-
-                Cow::Borrowed(self.as_os_str().as_bytes())
-            } else {
-                self.to_string_lossy_and_as_bytes()
-            }
-        }
-    }
-
-    fn to_string_lossy_and_as_bytes(&self) -> Cow<'_, [u8]> {
-        match self.to_string_lossy() {
-            Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
-            Cow::Owned(s) => Cow::Owned(s.into_bytes()),
-        }
+        Cow::Borrowed(self.as_os_str().as_bytes())
     }
 }
 
@@ -1,32 +1,118 @@
 #![forbid(unsafe_code)]
 
 use serde::{
-    de::{Deserializer, EnumAccess, Error, VariantAccess, Visitor},
+    de::{Deserializer, EnumAccess, VariantAccess, Visitor},
     Deserialize,
 };
-use std::{fmt, marker::PhantomData};
+use ssh_format::from_bytes;
+use std::{convert::TryInto, fmt, marker::PhantomData};
 
 use super::constants;
 
+/// `(name, value)` of an extension in the hello message, kept as bytes
+/// since neither is required to be UTF-8.
+pub type Extension = (Box<[u8]>, Box<[u8]>);
+
 /// **WARNING: Response can only be used with ssh_mux_format, which treats
 /// tuple and struct as the same.**
 #[derive(Clone, Debug)]
 pub enum Response {
-    Hello { version: u32 },
+    /// `extensions` are the `(name, value)` pairs following the version.
+    Hello {
+        version: u32,
+        extensions: Vec<Extension>,
+    },
+
+    Alive {
+        response_id: u32,
+        server_pid: u32,
+    },
 
-    Alive { response_id: u32, server_pid: u32 },
+    Ok {
+        response_id: u32,
+    },
+    Failure {
+        response_id: u32,
+        reason: Box<str>,
+    },
 
-    Ok { response_id: u32 },
-    Failure { response_id: u32, reason: Box<str> },
+    PermissionDenied {
+        response_id: u32,
+        reason: Box<str>,
+    },
 
-    PermissionDenied { response_id: u32, reason: Box<str> },
+    SessionOpened {
+        response_id: u32,
+        session_id: u32,
+    },
+    ExitMessage {
+        session_id: u32,
+        exit_value: u32,
+    },
+    TtyAllocFail {
+        session_id: u32,
+    },
 
-    SessionOpened { response_id: u32, session_id: u32 },
-    ExitMessage { session_id: u32, exit_value: u32 },
-    TtyAllocFail { session_id: u32 },
+    RemotePort {
+        response_id: u32,
+        remote_port: u32,
+    },
 
-    RemotePort { response_id: u32, remote_port: u32 },
+    /// Message of type unknown to this crate, e.g. `MUX_S_PROXY` or
+    /// vendor-specific ones, with `payload` being the rest of the packet.
+    Unknown {
+        msg_type: u32,
+        payload: Vec<u8>,
+    },
 }
+impl Response {
+    /// Deserialize `packet` without its header.
+    ///
+    /// Unlike `Deserialize`, this also parses the extensions of
+    /// `Response::Hello` and the payload of `Response::Unknown`, which
+    /// extend to the end of the packet.
+    pub(crate) fn from_packet(packet: &[u8]) -> super::Result<Self> {
+        // Ignore any trailing bytes to be forward compatible
+        let (mut response, mut rest) = from_bytes(packet)?;
+
+        match &mut response {
+            Response::Hello { extensions, .. } => {
+                while !rest.is_empty() {
+                    let ((name, value), trailing): ((&[u8], &[u8]), _) = from_bytes(rest)?;
+                    extensions.push((name.into(), value.into()));
+                    rest = trailing;
+                }
+            }
+            Response::Unknown { payload, .. } => payload.extend_from_slice(rest),
+            _ => (),
+        }
+
+        Ok(response)
+    }
+
+    /// Return id of the request this is a response to, or `None` if it
+    /// does not respond to any request.
+    ///
+    /// Unknown messages are assumed to start with the request id like
+    /// all other responses to requests.
+    pub(crate) fn response_id(&self) -> Option<u32> {
+        match self {
+            Response::Alive { response_id, .. }
+            | Response::Ok { response_id }
+            | Response::Failure { response_id, .. }
+            | Response::PermissionDenied { response_id, .. }
+            | Response::SessionOpened { response_id, .. }
+            | Response::RemotePort { response_id, .. } => Some(*response_id),
+            Response::Unknown { payload, .. } => payload
+                .get(..4)
+                .map(|response_id| u32::from_be_bytes(response_id.try_into().unwrap())),
+            Response::Hello { .. }
+            | Response::ExitMessage { .. }
+            | Response::TtyAllocFail { .. } => None,
+        }
+    }
+}
+
 impl<'de> Deserialize<'de> for Response {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
         deserializer.deserialize_enum(
@@ -67,7 +153,10 @@ impl<'de> Visitor<'de> for ResponseVisitor {
         match index {
             MUX_MSG_HELLO => {
                 let version: u32 = accessor.newtype_variant_seed(PhantomData)?;
-                Ok(Response::Hello { version })
+                Ok(Response::Hello {
+                    version,
+                    extensions: Vec::new(),
+                })
             }
             MUX_S_ALIVE => {
                 let tup: (u32, u32) = accessor.newtype_variant_seed(PhantomData)?;
@@ -119,7 +208,13 @@ impl<'de> Visitor<'de> for ResponseVisitor {
                     remote_port: tup.1,
                 })
             }
-            _ => Err(A::Error::custom("Unexpected packet type")),
+            msg_type => {
+                accessor.unit_variant()?;
+                Ok(Response::Unknown {
+                    msg_type,
+                    payload: Vec::new(),
+                })
+            }
         }
     }
 }
@@ -1,12 +1,20 @@
 #![forbid(unsafe_code)]
 
-use super::{Connection, Error, ErrorExt, Response, Result};
+use super::{proto::Event, Connection, Error, ErrorExt, Result};
 
 use std::io::ErrorKind;
 
-enum EstablishedSessionState {
-    Exited(Option<u32>),
+use tokio::net::unix::pipe;
+
+/// Change of session status returned by [`EstablishedSession::wait_event`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum SessionEvent {
+    /// Remote ssh server failed to allocate a tty, you can now return the tty
+    /// to cooked mode.
     TtyAllocFail,
+
+    /// The process on the remote machine has exited with `exit_value`.
+    Exited { exit_value: Option<u32> },
 }
 
 /// NOTE that once `EstablishedSession` is dropped, any data written to
@@ -15,51 +23,33 @@ enum EstablishedSessionState {
 ///
 /// # Cancel safety
 ///
-/// All methods of this struct is not cancellation safe.
+/// [`EstablishedSession::wait_event`] is cancellation safe.
+///
+/// [`EstablishedSession::wait`] is also cancellation safe, but it consumes
+/// `self` so the session would be dropped on cancellation.
 #[derive(Debug)]
 pub struct EstablishedSession {
     pub(super) conn: Connection,
-    pub(super) session_id: u32,
 }
 impl EstablishedSession {
-    fn check_session_id(&self, session_id: u32) -> Result<()> {
-        if self.session_id != session_id {
-            Err(Error::UnmatchedSessionId)
-        } else {
-            Ok(())
-        }
-    }
-
-    /// Return None if TtyAllocFail, Some(...) if the process exited.
-    async fn wait_impl(&mut self) -> Result<EstablishedSessionState> {
-        use Response::*;
-
-        let response = match self.conn.read_response().await {
-            Result::Ok(response) => response,
-            Err(err) => match &err {
-                Error::IOError(io_err) if io_err.kind() == ErrorKind::UnexpectedEof => {
-                    return Result::Ok(EstablishedSessionState::Exited(None))
-                }
-                _ => return Err(err),
-            },
-        };
-
-        match response {
-            TtyAllocFail { session_id } => {
-                self.check_session_id(session_id)?;
-                Result::Ok(EstablishedSessionState::TtyAllocFail)
-            }
-            ExitMessage {
-                session_id,
-                exit_value,
-            } => {
-                self.check_session_id(session_id)?;
-                Result::Ok(EstablishedSessionState::Exited(Some(exit_value)))
-            }
-            response => Err(Error::invalid_server_response(
+    /// Wait for session status to change.
+    ///
+    /// Unlike [`EstablishedSession::wait`], it does not consume `self`,
+    /// so it can be used in `tokio::select!` and be retried on cancellation.
+    ///
+    /// If the server close the connection without sending anything,
+    /// this function would return `Ok(SessionEvent::Exited { exit_value: None })`.
+    pub async fn wait_event(&mut self) -> Result<SessionEvent> {
+        match self.conn.next_event().await {
+            Ok(Event::Session { event, .. }) => Ok(event),
+            Ok(event) => Err(Error::invalid_server_response(
                 &"TtyAllocFail or ExitMessage",
-                &response,
+                &event,
             )),
+            Err(Error::IOError(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                Ok(SessionEvent::Exited { exit_value: None })
+            }
+            Err(err) => Err(err),
         }
     }
 
@@ -71,11 +61,9 @@ impl EstablishedSession {
     /// If the server close the connection without sending anything,
     /// this function would return `Ok(None)`.
     pub async fn wait(mut self) -> Result<SessionStatus, (Error, Self)> {
-        use EstablishedSessionState::*;
-
-        match self.wait_impl().await {
-            Ok(Exited(exit_value)) => Ok(SessionStatus::Exited { exit_value }),
-            Ok(TtyAllocFail) => Ok(SessionStatus::TtyAllocFail(self)),
+        match self.wait_event().await {
+            Ok(SessionEvent::Exited { exit_value }) => Ok(SessionStatus::Exited { exit_value }),
+            Ok(SessionEvent::TtyAllocFail) => Ok(SessionStatus::TtyAllocFail(self)),
             Err(err) => Err((err, self)),
         }
     }
@@ -93,3 +81,19 @@ pub enum SessionStatus {
     /// The process on the remote machine has exited with `exit_value`.
     Exited { exit_value: Option<u32> },
 }
+
+/// Async handles to the stdio of a remote process, returned by
+/// [`Connection::open_new_session_piped`] along with its [`EstablishedSession`].
+///
+/// Dropping `stdin` closes it, which signals eof to the remote process.
+#[derive(Debug)]
+pub struct RemoteChild {
+    /// Writer to the stdin of the remote process.
+    pub stdin: Option<pipe::Sender>,
+
+    /// Reader of the stdout of the remote process.
+    pub stdout: Option<pipe::Receiver>,
+
+    /// Reader of the stderr of the remote process.
+    pub stderr: Option<pipe::Receiver>,
+}
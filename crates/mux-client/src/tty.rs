@@ -0,0 +1,188 @@
+#![forbid(unsafe_code)]
+
+//! Interactive sessions with a local terminal, the equivalent of `ssh -t`.
+
+use super::{Connection, Result, Session, SessionEvent};
+
+use std::{
+    io,
+    os::unix::io::{AsFd, OwnedFd},
+};
+
+use rustix::termios::{tcgetattr, tcsetattr, OptionalActions, Termios};
+
+/// Puts a terminal into raw mode and restores its original mode on drop,
+/// including when unwinding from a panic.
+#[derive(Debug)]
+pub struct RawModeGuard {
+    fd: OwnedFd,
+    original: Option<Termios>,
+}
+
+impl RawModeGuard {
+    /// Put the terminal `fd` refers to into raw mode.
+    ///
+    /// `fd` is duplicated, so it can be closed while the guard is alive.
+    pub fn new<Fd: AsFd>(fd: Fd) -> io::Result<Self> {
+        let fd = fd.as_fd().try_clone_to_owned()?;
+
+        let original = tcgetattr(&fd)?;
+
+        let mut raw = original.clone();
+        raw.make_raw();
+        tcsetattr(&fd, OptionalActions::Flush, &raw)?;
+
+        Ok(Self {
+            fd,
+            original: Some(original),
+        })
+    }
+
+    /// Restore the terminal to its original mode.
+    ///
+    /// Calling it more than once is a no-op.
+    pub fn restore(&mut self) -> io::Result<()> {
+        if let Some(original) = self.original.take() {
+            tcsetattr(&self.fd, OptionalActions::Now, &original)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        // Errors cannot be reported in drop
+        self.restore().ok();
+    }
+}
+
+/// Run `session` with a tty allocated on the remote machine, connected to
+/// the local terminal `tty`, and return its exit value.
+///
+/// `session.tty` is ignored and always set to `true`.
+///
+/// `tty` is put into raw mode while the remote process is running and
+/// restored to its original mode when:
+///  - the server replies with `TtyAllocFail`;
+///  - the remote process exits;
+///  - an error occurs, the future is dropped or a panic unwinds.
+///
+/// `tty` must be in blocking mode.
+///
+/// Return `None` if the server closed the connection without sending
+/// the exit value.
+pub async fn run_interactive<Fd: AsFd>(
+    conn: Connection,
+    session: &Session<'_>,
+    tty: Fd,
+) -> Result<Option<u32>> {
+    let tty = tty.as_fd();
+
+    let session = Session {
+        tty: true,
+        ..session.clone()
+    };
+
+    let mut guard = RawModeGuard::new(tty)?;
+
+    let mut established_session = conn
+        .open_new_session_with_fds(&session, tty, tty, tty)
+        .await?;
+
+    loop {
+        match established_session.wait_event().await? {
+            SessionEvent::TtyAllocFail => guard.restore()?,
+            SessionEvent::Exited { exit_value } => {
+                guard.restore()?;
+                break Ok(exit_value);
+            }
+        }
+    }
+}
+
+/// Same as [`run_interactive`], but uses stdin of the current process
+/// as the local terminal.
+pub async fn run_interactive_stdin(conn: Connection, session: &Session<'_>) -> Result<Option<u32>> {
+    run_interactive(conn, session, io::stdin()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{borrow::Cow, convert::TryInto, fs::OpenOptions, panic};
+
+    use rustix::{
+        pty::{grantpt, openpt, ptsname, unlockpt, OpenptFlags},
+        termios::LocalModes,
+    };
+
+    /// Return (controller, user) of a new pty pair.
+    fn open_pty() -> (OwnedFd, OwnedFd) {
+        let controller = openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY).unwrap();
+        grantpt(&controller).unwrap();
+        unlockpt(&controller).unwrap();
+
+        let name = ptsname(&controller, Vec::new()).unwrap();
+        let user = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(name.to_str().unwrap())
+            .unwrap();
+
+        (controller, user.into())
+    }
+
+    fn is_raw(fd: &OwnedFd) -> bool {
+        !tcgetattr(fd)
+            .unwrap()
+            .local_modes
+            .intersects(LocalModes::ICANON | LocalModes::ECHO)
+    }
+
+    #[test]
+    fn test_raw_mode_guard() {
+        let (_controller, user) = open_pty();
+        assert!(!is_raw(&user));
+
+        let mut guard = RawModeGuard::new(&user).unwrap();
+        assert!(is_raw(&user));
+
+        guard.restore().unwrap();
+        assert!(!is_raw(&user));
+
+        drop(guard);
+        assert!(!is_raw(&user));
+    }
+
+    #[test]
+    fn test_raw_mode_guard_panic() {
+        let (_controller, user) = open_pty();
+
+        panic::catch_unwind(|| {
+            let _guard = RawModeGuard::new(&user).unwrap();
+            assert!(is_raw(&user));
+            panic!("Panic while in raw mode");
+        })
+        .unwrap_err();
+
+        assert!(!is_raw(&user));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unordered_run_interactive() {
+        let (_controller, user) = open_pty();
+
+        let conn = Connection::connect("/tmp/openssh-mux-client-test.socket")
+            .await
+            .unwrap();
+
+        let session = Session::builder()
+            .cmd(Cow::Borrowed("exit 3".try_into().unwrap()))
+            .build();
+
+        let exit_value = run_interactive(conn, &session, &user).await.unwrap();
+        assert_eq!(exit_value, Some(3));
+        assert!(!is_raw(&user));
+    }
+}
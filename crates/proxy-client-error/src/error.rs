@@ -1,4 +1,4 @@
-use std::io;
+use std::{io, sync::Arc};
 
 use thiserror::Error as ThisError;
 use tokio::task::JoinError;
@@ -46,13 +46,50 @@ pub enum Error {
     #[error("Received duplicate sender channel id {0} from sshd")]
     DuplicateSenderChannel(u32),
 
+    /// Channel is closed by sshd
+    #[error("Channel is closed by sshd")]
+    ChannelClosed,
+
+    /// Eof is already sent to the channel
+    #[error("Eof is already sent to the channel")]
+    ChannelEofSent,
+
+    /// Number of channels reached the limit of the client
+    #[error("Number of channels reached the limit")]
+    ChannelLimitReached,
+
+    /// Channel request is rejected by sshd
+    #[error("Channel request is rejected by sshd")]
+    ChannelRequestFailed,
+
     /// Receive unexpected response for channel request
     #[error("Receive unexpected response for channel request")]
     UnexpectedRequestResponse,
 
+    /// Received a packet larger than the maximum incoming packet size
+    #[error("Received packet of {size} bytes, which is larger than the limit {limit}")]
+    PacketTooLarge { size: u32, limit: u32 },
+
+    /// Received more data than the receiver window of the channel allows
+    #[error("Received {size} bytes of data, which exceeds the receiver window {window}")]
+    ReceiverWindowExceeded { size: u32, window: u32 },
+
     /// Tokio task failed
     #[error("tokio task failed: {0}")]
     JoinError(#[from] JoinError),
+
+    /// Future driving the read or write end is dropped before completion
+    #[error("Driver future is dropped before completion")]
+    DriverDropped,
+
+    /// The connection is shut down before sshd closes the channel
+    #[error("Connection is shut down before the channel is closed by sshd")]
+    ForcedShutdown,
+
+    /// The connection to sshd is broken due to a fatal error
+    /// in the read or write task.
+    #[error("Connection is broken: {0}")]
+    ConnectionBroken(#[source] Arc<Error>),
 }
 
 impl From<Error> for io::Error {
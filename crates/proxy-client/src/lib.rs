@@ -4,8 +4,13 @@ pub use error::Error;
 pub use openssh_proxy_client_error as error;
 
 mod proxy_client;
-pub use proxy_client::ProxyClient;
+pub use proxy_client::{
+    ChannelInput, ChannelOutput, ChannelStream, DriverFuture, ProcessStatus, ProxyClient,
+    ProxyClientBuilder, RemoteChild, RequestOutcome, ReuniteError, Session, WriteCoalescing,
+    WriteStats,
+};
 
 mod constants;
 mod request;
 mod response;
+pub use response::{ExitSignal, ExitStatus, SignalName};
@@ -0,0 +1,233 @@
+use std::{
+    fmt,
+    future::Future,
+    num::{NonZeroU32, NonZeroUsize},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
+};
+
+use super::{
+    create_read_task, create_write_task, default_max_incoming_packet_size, ChannelConfig,
+    ProxyClient, SharedData, TaskHandle, WriteCoalescing, DEFAULT_MAX_PACKET_SIZE,
+    DEFAULT_WINDOW_SIZE,
+};
+
+/// Future that drives the read or write end of a [`ProxyClient`].
+///
+/// It must be polled to completion for the [`ProxyClient`] to make progress.
+#[must_use = "futures do nothing unless polled"]
+pub struct DriverFuture(Pin<Box<dyn Future<Output = ()> + Send>>);
+
+impl fmt::Debug for DriverFuture {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DriverFuture").finish_non_exhaustive()
+    }
+}
+
+impl Future for DriverFuture {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.0.as_mut().poll(cx)
+    }
+}
+
+/// Builder for [`ProxyClient`].
+#[derive(Clone, Debug)]
+pub struct ProxyClientBuilder {
+    reusable_io_slice_cap: NonZeroUsize,
+    window_size: NonZeroU32,
+    max_packet_size: NonZeroU32,
+    max_channels: Option<NonZeroUsize>,
+    max_incoming_packet_size: Option<NonZeroU32>,
+    read_buffer_cap: usize,
+    write_coalescing: Option<WriteCoalescing>,
+}
+
+impl Default for ProxyClientBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProxyClientBuilder {
+    pub fn new() -> Self {
+        Self {
+            reusable_io_slice_cap: NonZeroUsize::new(16).unwrap(),
+            window_size: NonZeroU32::new(DEFAULT_WINDOW_SIZE).unwrap(),
+            max_packet_size: NonZeroU32::new(DEFAULT_MAX_PACKET_SIZE).unwrap(),
+            max_channels: None,
+            max_incoming_packet_size: None,
+            read_buffer_cap: 1024,
+            write_coalescing: None,
+        }
+    }
+
+    /// Determines how many `Bytes` can be sent in one syscall to reduce
+    /// overhead, defaults to 16.
+    pub fn reusable_io_slice_cap(&mut self, cap: NonZeroUsize) -> &mut Self {
+        self.reusable_io_slice_cap = cap;
+        self
+    }
+
+    /// Receiver window size of new channels, defaults to 2M, same as openssh.
+    ///
+    /// Once the window is exhausted, it is extended by the same amount.
+    pub fn channel_window_size(&mut self, window_size: NonZeroU32) -> &mut Self {
+        self.window_size = window_size;
+        self
+    }
+
+    /// Max packet size advertised for new channels, defaults to 32K,
+    /// same as openssh.
+    pub fn channel_max_packet_size(&mut self, max_packet_size: NonZeroU32) -> &mut Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Maximum number of channels alive, unlimited by default.
+    ///
+    /// Opening a channel waits for a free slot once the limit is reached.
+    /// A channel is counted until it is closed by sshd and
+    /// all of its handles are dropped.
+    ///
+    /// It is useful for keeping the number of sessions under `MaxSessions`
+    /// of sshd, which otherwise fails the open requests.
+    ///
+    /// # Panic
+    ///
+    /// If `max_channels` is larger than [`tokio::sync::Semaphore::MAX_PERMITS`].
+    pub fn max_channels(&mut self, max_channels: NonZeroUsize) -> &mut Self {
+        assert!(max_channels.get() <= tokio::sync::Semaphore::MAX_PERMITS);
+        self.max_channels = Some(max_channels);
+        self
+    }
+
+    /// Maximum size of incoming packets, check
+    /// [`ProxyClient::set_max_incoming_packet_size`] for more information.
+    ///
+    /// Defaults to 35000 plus the max packet size of channels.
+    pub fn max_incoming_packet_size(&mut self, size: NonZeroU32) -> &mut Self {
+        self.max_incoming_packet_size = Some(size);
+        self
+    }
+
+    /// Initial capacity of the buffer used by the read task,
+    /// defaults to 1024.
+    ///
+    /// The buffer grows as needed.
+    pub fn read_buffer_cap(&mut self, cap: usize) -> &mut Self {
+        self.read_buffer_cap = cap;
+        self
+    }
+
+    /// Coalesce small writes to reduce the number of write syscalls,
+    /// disabled by default.
+    pub fn write_coalescing(&mut self, coalescing: WriteCoalescing) -> &mut Self {
+        self.write_coalescing = Some(coalescing);
+        self
+    }
+
+    /// Create the [`ProxyClient`] and spawn the read and write task using
+    /// [`tokio::spawn`].
+    ///
+    /// Must be called in the context of a tokio runtime.
+    pub fn build<R, W>(&self, rx: R, tx: W) -> ProxyClient
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let shared_data = self.create_shared_data();
+
+        ProxyClient {
+            read_task: TaskHandle::Tokio(tokio::spawn(create_read_task(
+                rx,
+                shared_data.clone(),
+                self.read_buffer_cap,
+            ))),
+            write_task: TaskHandle::Tokio(tokio::spawn(create_write_task(
+                tx,
+                shared_data.clone(),
+                self.reusable_io_slice_cap,
+                self.write_coalescing,
+            ))),
+            shared_data,
+        }
+    }
+
+    /// Create the [`ProxyClient`] and spawn the read and write task using
+    /// `spawn`, e.g. on a `LocalSet` or a dedicated runtime.
+    pub fn build_with_spawner<R, W, S>(&self, rx: R, tx: W, mut spawn: S) -> ProxyClient
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+        S: FnMut(DriverFuture),
+    {
+        let (proxy_client, read_driver, write_driver) = self.build_drivers(rx, tx);
+
+        spawn(read_driver);
+        spawn(write_driver);
+
+        proxy_client
+    }
+
+    /// Create the [`ProxyClient`] and return the futures driving its
+    /// read and write end, without spawning any task.
+    ///
+    /// Both futures must be polled concurrently, otherwise the
+    /// [`ProxyClient`] will not make any progress.
+    pub fn build_drivers<R, W>(&self, rx: R, tx: W) -> (ProxyClient, DriverFuture, DriverFuture)
+    where
+        R: AsyncRead + Send + 'static,
+        W: AsyncWrite + Send + 'static,
+    {
+        let shared_data = self.create_shared_data();
+
+        let (read_sender, read_receiver) = oneshot::channel();
+        let (write_sender, write_receiver) = oneshot::channel();
+
+        let read_task = create_read_task(rx, shared_data.clone(), self.read_buffer_cap);
+        let write_task = create_write_task(
+            tx,
+            shared_data.clone(),
+            self.reusable_io_slice_cap,
+            self.write_coalescing,
+        );
+
+        let read_driver = DriverFuture(Box::pin(async move {
+            // The receiver might have been dropped along with `ProxyClient`.
+            read_sender.send(read_task.await).ok();
+        }));
+        let write_driver = DriverFuture(Box::pin(async move {
+            write_sender.send(write_task.await).ok();
+        }));
+
+        let proxy_client = ProxyClient {
+            shared_data,
+            read_task: TaskHandle::Driver(read_receiver),
+            write_task: TaskHandle::Driver(write_receiver),
+        };
+
+        (proxy_client, read_driver, write_driver)
+    }
+
+    fn create_shared_data(&self) -> SharedData {
+        let max_packet_size = self.max_packet_size.get();
+
+        SharedData::new(
+            ChannelConfig {
+                window_size: self.window_size.get(),
+                max_packet_size,
+            },
+            self.max_incoming_packet_size
+                .map(NonZeroU32::get)
+                .unwrap_or_else(|| default_max_incoming_packet_size(max_packet_size)),
+            self.max_channels,
+        )
+    }
+}
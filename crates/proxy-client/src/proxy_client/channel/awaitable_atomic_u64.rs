@@ -91,6 +91,12 @@ impl AwaitableAtomicU64 {
             panic!("u64 is overflowed!")
         }
 
+        self.wake_up();
+    }
+
+    /// Wake up the reader without changing the counter, so that it can
+    /// check for other conditions, e.g. the connection being broken.
+    pub(crate) fn wake_up(&self) {
         let waker = self.waker.lock().unwrap().take();
 
         // Cal waker here to reduce critical section
@@ -1,7 +1,7 @@
 use std::{
     convert::TryInto,
     io, mem,
-    num::{NonZeroU32, NonZeroU64},
+    num::{NonZeroU32, NonZeroU8},
     pin::Pin,
     task::{Context, Poll},
 };
@@ -9,14 +9,10 @@ use std::{
 use bytes::{Bytes, BytesMut};
 use futures_util::{ready, Sink, SinkExt};
 use pin_project::{pin_project, pinned_drop};
-use tokio::io::AsyncWrite;
-use tokio_util::sync::WaitForCancellationFutureOwned;
+use tokio::{io::AsyncWrite, select};
 
 use super::ChannelRef;
-use crate::{
-    request::{ChannelEof, DataTransfer},
-    Error,
-};
+use crate::{request::DataTransfer, Error};
 
 /// Input of the Channel
 #[derive(Debug)]
@@ -26,6 +22,9 @@ pub struct ChannelInput {
 
     max_packet_size: NonZeroU32,
 
+    /// Weight of this channel when scheduling writes.
+    priority: NonZeroU8,
+
     /// Number of bytes one can send
     /// without waiting.
     curr_sender_win: u64,
@@ -35,12 +34,54 @@ pub struct ChannelInput {
     pending_len: usize,
 
     buffer: BytesMut,
-
-    #[pin]
-    token: WaitForCancellationFutureOwned,
 }
 
 impl ChannelInput {
+    pub(super) fn new(channel_ref: ChannelRef, max_packet_size: NonZeroU32) -> Self {
+        Self {
+            channel_ref,
+            max_packet_size,
+            priority: NonZeroU8::new(1).unwrap(),
+            curr_sender_win: 0,
+            pending_bytes: Vec::new(),
+            pending_len: 0,
+            buffer: BytesMut::new(),
+        }
+    }
+
+    pub(super) fn channel_ref(&self) -> &ChannelRef {
+        &self.channel_ref
+    }
+
+    /// Id of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_ref.channel_id()
+    }
+
+    /// Max packet size advertised by sshd, data written is sent in
+    /// packets of at most this size.
+    pub fn peer_max_packet_size(&self) -> NonZeroU32 {
+        self.max_packet_size
+    }
+
+    /// Priority of this channel when sharing the connection with
+    /// other channels, defaults to 1.
+    pub fn priority(&self) -> NonZeroU8 {
+        self.priority
+    }
+
+    /// Set the priority of this channel.
+    ///
+    /// Data of all channels are written in a round-robin manner,
+    /// a channel with priority `n` can send `n` times as much data as
+    /// a channel with priority 1 in each round when both have data
+    /// to send.
+    ///
+    /// It takes effect on data sent after this call.
+    pub fn set_priority(self: Pin<&mut Self>, priority: NonZeroU8) {
+        *self.project().priority = priority;
+    }
+
     fn add_pending_byte(self: Pin<&mut Self>, bytes: Bytes) {
         let this = self.project();
 
@@ -60,7 +101,7 @@ impl ChannelInput {
     fn create_data_transfer_header(self: Pin<&mut Self>, n: u32) -> Result<Bytes, Error> {
         let this = self.project();
 
-        let channel_id = this.channel_ref.channel_id();
+        let channel_id = this.channel_ref.peer_channel_id();
 
         let buffer = this.buffer;
 
@@ -76,6 +117,11 @@ impl ChannelInput {
     fn try_flush(mut self: Pin<&mut Self>) -> Result<(), Error> {
         let this = self.as_mut().project();
 
+        // No data can be sent after close.
+        if this.channel_ref.channel_data.is_close_sent() {
+            return Err(Error::ChannelClosed);
+        }
+
         // Maximum number of bytes we can write to
         let max = this
             .max_packet_size
@@ -86,8 +132,6 @@ impl ChannelInput {
             return Ok(());
         }
 
-        let header = self.as_mut().create_data_transfer_header(max)?;
-
         let this = self.as_mut().project();
 
         let pending_bytes = this.pending_bytes;
@@ -132,16 +176,25 @@ impl ChannelInput {
             bytes_written += n;
         }
 
-        let mut drain = pending_bytes.drain(0..pending_end);
+        // The header has to carry the actual length of the data,
+        // which is only known now.
+        let header = self
+            .as_mut()
+            .create_data_transfer_header(bytes_written.try_into().unwrap())?;
+
+        let this = self.as_mut().project();
+
+        let mut drain = this.pending_bytes.drain(0..pending_end);
 
         this.channel_ref
             .shared_data
-            .get_write_channel()
-            .add_more_data(
-                1 + drain.len() + maybe_last_bytes.iter().len(),
+            .get_write_scheduler()
+            .push_data(
+                this.channel_ref.channel_id(),
+                *this.priority,
                 Some(header)
                     .into_iter()
-                    // Use mutable alias to drain since add_more_data internally
+                    // Use mutable alias to drain since push_data internally
                     // holds a mutex, so here we drop `drain` outside of it to
                     // reduce critical section.
                     .chain(&mut drain)
@@ -164,18 +217,40 @@ impl Sink<Bytes> for ChannelInput {
         let this = self.project();
 
         if *this.curr_sender_win == 0 {
-            *this.curr_sender_win = ready!(this
-                .channel_ref
-                .channel_data
-                .sender_window_size
-                .poll_until_non_zero(cx)
-                .map(NonZeroU64::get));
+            let sender_window_size = &this.channel_ref.channel_data.sender_window_size;
+
+            match sender_window_size.poll_until_non_zero(cx) {
+                Poll::Ready(win) => *this.curr_sender_win = win.get(),
+                // Check for error after the waker is registered,
+                // so that the wakeup on error cannot be missed.
+                Poll::Pending => {
+                    return match this.channel_ref.shared_data.fatal_error() {
+                        Some(err) => Poll::Ready(Err(err)),
+                        None if this.channel_ref.channel_data.state.is_closed()
+                            || this.channel_ref.channel_data.is_close_sent() =>
+                        {
+                            Poll::Ready(Err(Error::ChannelClosed))
+                        }
+                        None => Poll::Pending,
+                    }
+                }
+            }
         }
 
         Poll::Ready(Ok(()))
     }
 
     fn start_send(mut self: Pin<&mut Self>, bytes: Bytes) -> Result<(), Self::Error> {
+        if let Some(err) = self.channel_ref.shared_data.fatal_error() {
+            return Err(err);
+        }
+        if self.channel_ref.channel_data.is_close_sent() {
+            return Err(Error::ChannelClosed);
+        }
+        if self.channel_ref.channel_data.is_eof_sent() {
+            return Err(Error::ChannelEofSent);
+        }
+
         if !bytes.is_empty() {
             self.as_mut().add_pending_byte(bytes);
 
@@ -210,8 +285,13 @@ impl Sink<Bytes> for ChannelInput {
         Poll::Ready(Ok(()))
     }
 
-    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Sink::poll_flush(self, cx)
+    /// Flush and then send eof, data can still be received afterwards.
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(Sink::poll_flush(self.as_mut(), cx))?;
+
+        self.send_eof_packet();
+
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -286,23 +366,7 @@ impl AsyncWrite for ChannelInput {
 
 impl ChannelInput {
     fn send_eof_packet(self: Pin<&mut Self>) {
-        let this = self.project();
-
-        let channel_id = this.channel_ref.channel_id();
-
-        let buffer = this.buffer;
-        debug_assert!(buffer.is_empty());
-        buffer.clear();
-
-        ChannelEof::new(channel_id)
-            .serialize_with_header(buffer, 0)
-            .expect("Serialization should not fail here");
-        let bytes = buffer.split().freeze();
-
-        this.channel_ref
-            .shared_data
-            .get_write_channel()
-            .push_bytes(bytes);
+        self.channel_ref.send_eof();
     }
 }
 
@@ -329,27 +393,34 @@ impl PinnedDrop for ChannelInput {
                 let new_channel_input = ChannelInput {
                     channel_ref: this.channel_ref.clone(),
                     max_packet_size: *this.max_packet_size,
+                    priority: *this.priority,
                     curr_sender_win: *this.curr_sender_win,
 
                     pending_bytes: mem::take(this.pending_bytes),
                     pending_len: mem::take(this.pending_len),
 
                     buffer: mem::take(this.buffer),
-
-                    token: this
-                        .channel_ref
-                        .shared_data
-                        .get_cancellation_token()
-                        .clone()
-                        .cancelled_owned(),
                 };
+                let cancellation_token = this
+                    .channel_ref
+                    .shared_data
+                    .get_cancellation_token()
+                    .clone();
+
                 tokio::spawn(async move {
-                    tokio::pin!(new_channel_input);
-                    if new_channel_input.as_mut().close().await.is_err() {
+                    let mut new_channel_input = new_channel_input;
+
+                    let res = select! {
+                        res = new_channel_input.close() => res,
+                        // Give up once the connection is shut down.
+                        _ = cancellation_token.cancelled() => Err(Error::ChannelClosed),
+                    };
+
+                    if res.is_err() {
                         // Make sure drop implementation would send eof packet
                         // instead of trying to flush the data again
                         // or create yet another task.
-                        new_channel_input.project().pending_bytes.clear();
+                        new_channel_input.pending_bytes.clear();
                     }
                 });
             }
@@ -14,6 +14,7 @@ use futures_util::{
 use tokio::io::{AsyncBufRead, AsyncRead, ReadBuf};
 
 use super::{ChannelRef, MpscBytesChannel};
+use crate::Error;
 
 #[derive(Debug)]
 pub struct ChannelOutput {
@@ -27,12 +28,46 @@ pub struct ChannelOutput {
     fifo: Vec<Bytes>,
 
     is_eof: bool,
+
+    /// Set if eof is caused by the connection being broken.
+    is_broken: bool,
 }
 
 impl ChannelOutput {
+    pub(super) fn new(channel_ref: ChannelRef, channel: Arc<MpscBytesChannel>) -> Self {
+        Self {
+            channel_ref,
+            channel,
+            fifo: Vec::new(),
+            is_eof: false,
+            is_broken: false,
+        }
+    }
+
+    pub(super) fn channel_ref(&self) -> &ChannelRef {
+        &self.channel_ref
+    }
+
+    /// Return true if it reads from rx (stdout) instead of stderr.
+    pub(super) fn is_rx(&self) -> bool {
+        self.channel_ref
+            .channel_data
+            .rx
+            .as_ref()
+            .is_some_and(|rx| Arc::ptr_eq(rx, &self.channel))
+    }
+
+    /// Id of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_ref.channel_id()
+    }
+
     /// If self.fifo is not empty, ret.
     /// Otherwise poll for data.
-    fn poll_for_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    ///
+    /// Return an error if the connection is broken and
+    /// all data received are consumed.
+    fn poll_for_data(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
         let this = Pin::into_inner(self);
 
         if !this.is_eof && this.fifo.is_empty() {
@@ -46,21 +81,28 @@ impl ChannelOutput {
             fifo.reverse();
 
             this.is_eof = fifo.is_empty();
+            this.is_broken = this.is_eof && this.channel.is_broken();
         }
 
-        Poll::Ready(())
+        if this.is_broken {
+            if let Some(err) = this.channel_ref.shared_data.fatal_error() {
+                return Poll::Ready(Err(err));
+            }
+        }
+
+        Poll::Ready(Ok(()))
     }
 }
 
 impl Stream for ChannelOutput {
-    type Item = Bytes;
+    type Item = Result<Bytes, Error>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        ready!(self.as_mut().poll_for_data(cx));
+        ready!(self.as_mut().poll_for_data(cx))?;
 
         // If self.is_eof == true, then self.fifo.pop() would return None.
         // Otherwise, it would return Some.
-        Poll::Ready(self.fifo.pop())
+        Poll::Ready(self.fifo.pop().map(Ok))
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
@@ -76,7 +118,7 @@ impl FusedStream for ChannelOutput {
 
 impl AsyncBufRead for ChannelOutput {
     fn poll_fill_buf(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
-        ready!(self.as_mut().poll_for_data(cx));
+        ready!(self.as_mut().poll_for_data(cx))?;
 
         Poll::Ready(Ok(Pin::into_inner(self)
             .fifo
@@ -116,13 +158,22 @@ impl AsyncRead for ChannelOutput {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+
         loop {
             let remaining = buf.remaining();
             if remaining == 0 {
                 break Poll::Ready(Ok(()));
             }
 
-            let slice = ready!(self.as_mut().poll_fill_buf(cx))?;
+            let slice = match self.as_mut().poll_fill_buf(cx) {
+                // Return what has been read so far, any error would
+                // be returned by the next call.
+                Poll::Pending | Poll::Ready(Err(_)) if buf.filled().len() != filled => {
+                    break Poll::Ready(Ok(()))
+                }
+                poll => ready!(poll)?,
+            };
             if slice.is_empty() {
                 break Poll::Ready(Ok(()));
             }
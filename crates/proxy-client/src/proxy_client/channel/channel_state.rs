@@ -2,7 +2,7 @@ use std::{
     future::Future,
     mem,
     pin::Pin,
-    sync::{Mutex, MutexGuard},
+    sync::{Arc, Mutex, MutexGuard},
     task::{Context, Poll, Waker},
 };
 
@@ -20,6 +20,15 @@ pub(crate) struct ChannelState(Mutex<Inner>);
 struct Inner {
     state: State,
     waker: Option<Waker>,
+
+    /// Set if the connection is broken.
+    fatal_error: Option<Arc<Error>>,
+
+    /// Set once close is received from sshd.
+    closed: bool,
+
+    /// Set once the channel is confirmed, kept after the process exits.
+    peer_channel_id: Option<u32>,
 }
 
 /// Expected state transition:
@@ -36,6 +45,7 @@ enum State {
 
     OpenChannelRequestConfirmed {
         max_packet_size: u32,
+        peer_channel_id: u32,
     },
 
     OpenChannelRequestFailed(OpenFailure),
@@ -52,12 +62,16 @@ pub(crate) enum OpenChannelRes {
     /// Ok and confirmed
     Confirmed {
         max_packet_size: u32,
+        /// Channel id allocated by sshd, used as the recipient channel
+        /// of packets sent.
+        peer_channel_id: u32,
     },
     Failed(OpenFailure),
 }
 
-#[derive(Debug)]
-pub(crate) enum ProcessStatus {
+/// How the remote process terminated.
+#[derive(Clone, Debug)]
+pub enum ProcessStatus {
     ProcessExited(ExitStatus),
     ProcessKilled(ExitSignal),
 }
@@ -69,6 +83,25 @@ pub(crate) struct OpenChannelRequestedInner {
     /// The number of bytes `extend_window_size_packet` will extend
     /// the receiver window size.
     pub(crate) extend_window_size: u32,
+
+    /// Set if the opener gave up waiting for the response, in which
+    /// case the channel read task is responsible for cleaning up.
+    pub(crate) abandoned: bool,
+}
+
+/// What the opener has to clean up after abandoning the channel.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Abandoned {
+    /// The response is not received yet, the channel read task would
+    /// clean up once it is.
+    Pending,
+
+    /// The channel is confirmed and has to be closed.
+    Confirmed { peer_channel_id: u32 },
+
+    /// The channel failed to open or the connection is broken,
+    /// so it has to be removed.
+    Failed,
 }
 
 /// For the channel users
@@ -80,29 +113,45 @@ impl ChannelState {
             state: State::OpenChannelRequested(OpenChannelRequestedInner {
                 init_receiver_win_size,
                 extend_window_size,
+                abandoned: false,
             }),
             waker: None,
+            fatal_error: None,
+            closed: false,
+            peer_channel_id: None,
         }))
     }
 
-    pub(crate) fn wait_for_confirmation(&self) -> impl Future<Output = OpenChannelRes> + '_ {
+    /// Return [`Error::ConnectionBroken`] if the connection is broken
+    /// before the response is received.
+    pub(crate) fn wait_for_confirmation(
+        &self,
+    ) -> impl Future<Output = Result<OpenChannelRes, Error>> + '_ {
         struct WaitForConfirmation<'a>(&'a ChannelState);
 
         impl Future for WaitForConfirmation<'_> {
-            type Output = OpenChannelRes;
+            type Output = Result<OpenChannelRes, Error>;
 
             fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
                 let mut guard = self.0 .0.lock().unwrap();
 
                 match guard.state {
                     State::OpenChannelRequested { .. } => {
+                        if let Some(err) = &guard.fatal_error {
+                            return Poll::Ready(Err(Error::ConnectionBroken(err.clone())));
+                        }
+
                         ChannelState::install_new_waker(guard, cx);
 
                         Poll::Pending
                     }
-                    State::OpenChannelRequestConfirmed { max_packet_size } => {
-                        Poll::Ready(OpenChannelRes::Confirmed { max_packet_size })
-                    }
+                    State::OpenChannelRequestConfirmed {
+                        max_packet_size,
+                        peer_channel_id,
+                    } => Poll::Ready(Ok(OpenChannelRes::Confirmed {
+                        max_packet_size,
+                        peer_channel_id,
+                    })),
                     State::OpenChannelRequestFailed(..) => {
                         let prev_state = mem::replace(&mut guard.state, State::Consumed);
 
@@ -110,7 +159,7 @@ impl ChannelState {
                         drop(guard);
 
                         if let State::OpenChannelRequestFailed(err) = prev_state {
-                            Poll::Ready(OpenChannelRes::Failed(err))
+                            Poll::Ready(Ok(OpenChannelRes::Failed(err)))
                         } else {
                             unreachable!()
                         }
@@ -125,17 +174,30 @@ impl ChannelState {
 
     /// Must be called after `wait_for_confirmation` returns
     /// `OpenChannelRes::Confirmed`
-    pub(crate) fn wait_for_process_exit(&self) -> impl Future<Output = ProcessStatus> + '_ {
+    ///
+    /// Return `None` if the channel is closed without exit status, or
+    /// [`Error::ConnectionBroken`] if the connection is broken
+    /// before the process exits.
+    pub(crate) fn wait_for_process_exit(
+        &self,
+    ) -> impl Future<Output = Result<Option<ProcessStatus>, Error>> + '_ {
         struct WaitForProcessExit<'a>(&'a ChannelState);
 
         impl Future for WaitForProcessExit<'_> {
-            type Output = ProcessStatus;
+            type Output = Result<Option<ProcessStatus>, Error>;
 
             fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
                 let mut guard = self.0 .0.lock().unwrap();
 
                 match guard.state {
                     State::OpenChannelRequestConfirmed { .. } => {
+                        if let Some(err) = &guard.fatal_error {
+                            return Poll::Ready(Err(Error::ConnectionBroken(err.clone())));
+                        }
+                        if guard.closed {
+                            return Poll::Ready(Ok(None));
+                        }
+
                         ChannelState::install_new_waker(guard, cx);
 
                         Poll::Pending
@@ -146,7 +208,7 @@ impl ChannelState {
                         // Release lock
                         drop(guard);
 
-                        Poll::Ready(match prev_state {
+                        Poll::Ready(Ok(Some(match prev_state {
                             State::ProcessExited(exit_status) => {
                                 ProcessStatus::ProcessExited(exit_status)
                             }
@@ -154,7 +216,7 @@ impl ChannelState {
                                 ProcessStatus::ProcessKilled(exit_signal)
                             }
                             _ => unreachable!(),
-                        })
+                        })))
                     }
                     _ => panic!("Unexpected state"),
                 }
@@ -164,6 +226,40 @@ impl ChannelState {
         WaitForProcessExit(self)
     }
 
+    /// Called if the opener gives up before `wait_for_confirmation`
+    /// returns.
+    pub(crate) fn abandon(&self) -> Abandoned {
+        let mut guard = self.0.lock().unwrap();
+
+        let is_broken = guard.fatal_error.is_some();
+
+        match &mut guard.state {
+            State::OpenChannelRequested(_) if is_broken => Abandoned::Failed,
+            State::OpenChannelRequested(inner) => {
+                inner.abandoned = true;
+                Abandoned::Pending
+            }
+            State::OpenChannelRequestConfirmed {
+                peer_channel_id, ..
+            } => Abandoned::Confirmed {
+                peer_channel_id: *peer_channel_id,
+            },
+            State::OpenChannelRequestFailed(..) => Abandoned::Failed,
+            _ => panic!("Unexpected state"),
+        }
+    }
+
+    /// Return true if close is received from sshd.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.0.lock().unwrap().closed
+    }
+
+    /// Return the channel id allocated by sshd, or `None` if
+    /// the channel is not confirmed yet.
+    pub(crate) fn peer_channel_id(&self) -> Option<u32> {
+        self.0.lock().unwrap().peer_channel_id
+    }
+
     fn install_new_waker(mut guard: MutexGuard<'_, Inner>, cx: &mut Context<'_>) {
         let prev_waker = mem::replace(&mut guard.waker, Some(cx.waker().clone()));
 
@@ -184,10 +280,21 @@ impl ChannelState {
         let mut guard = self.0.lock().unwrap();
 
         if let State::OpenChannelRequested(inner) = guard.state {
+            if let OpenChannelRes::Confirmed {
+                peer_channel_id, ..
+            } = res
+            {
+                guard.peer_channel_id = Some(peer_channel_id);
+            }
+
             guard.state = match res {
-                OpenChannelRes::Confirmed { max_packet_size } => {
-                    State::OpenChannelRequestConfirmed { max_packet_size }
-                }
+                OpenChannelRes::Confirmed {
+                    max_packet_size,
+                    peer_channel_id,
+                } => State::OpenChannelRequestConfirmed {
+                    max_packet_size,
+                    peer_channel_id,
+                },
                 OpenChannelRes::Failed(err) => State::OpenChannelRequestFailed(err),
             };
 
@@ -223,6 +330,27 @@ impl ChannelState {
         }
     }
 
+    /// Called once close is received from sshd.
+    pub(crate) fn mark_closed(&self) {
+        let mut guard = self.0.lock().unwrap();
+
+        guard.closed = true;
+
+        Self::wakeup(guard);
+    }
+
+    /// Called when the connection is broken, so that any waiting
+    /// operation returns `err`.
+    pub(crate) fn abort(&self, err: &Arc<Error>) {
+        let mut guard = self.0.lock().unwrap();
+
+        if guard.fatal_error.is_none() {
+            guard.fatal_error = Some(err.clone());
+        }
+
+        Self::wakeup(guard);
+    }
+
     fn wakeup(mut guard: MutexGuard<'_, Inner>) {
         let waker = guard.waker.take();
 
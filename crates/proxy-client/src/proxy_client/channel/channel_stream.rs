@@ -0,0 +1,126 @@
+use std::{
+    error, fmt, io,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{ChannelInput, ChannelOutput};
+
+/// Both directions of a forwarding channel in one object, usable
+/// with functions like [`tokio::io::copy_bidirectional`].
+///
+/// [`AsyncWrite::poll_shutdown`] only sends eof, data can still be
+/// read until sshd sends eof too.
+#[derive(Debug)]
+pub struct ChannelStream {
+    input: ChannelInput,
+    output: ChannelOutput,
+}
+
+impl ChannelStream {
+    pub(super) fn new(input: ChannelInput, output: ChannelOutput) -> Self {
+        Self { input, output }
+    }
+
+    /// Id of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.input.channel_id()
+    }
+
+    /// Id of the channel allocated by sshd.
+    pub fn peer_channel_id(&self) -> u32 {
+        self.input.channel_ref().peer_channel_id()
+    }
+
+    /// Max packet size advertised by sshd.
+    pub fn peer_max_packet_size(&self) -> NonZeroU32 {
+        self.input.peer_max_packet_size()
+    }
+
+    /// Split it into the write half and the read half, which can be
+    /// put back together using [`ChannelStream::reunite`].
+    pub fn into_split(self) -> (ChannelInput, ChannelOutput) {
+        (self.input, self.output)
+    }
+
+    /// Put back the halves returned by [`ChannelStream::into_split`].
+    ///
+    /// Fail if they are not from the same `ChannelStream`.
+    // The error gives back both halves, just like `Ok`.
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(input: ChannelInput, output: ChannelOutput) -> Result<Self, ReuniteError> {
+        if Arc::ptr_eq(&input.channel_ref().0, &output.channel_ref().0) && output.is_rx() {
+            Ok(Self::new(input, output))
+        } else {
+            Err(ReuniteError(input, output))
+        }
+    }
+}
+
+impl AsyncRead for ChannelStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.output).poll_read(cx, buf)
+    }
+}
+
+impl AsyncBufRead for ChannelStream {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut Pin::into_inner(self).output).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.output).consume(amt)
+    }
+}
+
+impl AsyncWrite for ChannelStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.input.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_flush(cx)
+    }
+
+    /// Flush and send eof, reading is unaffected.
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.input).poll_shutdown(cx)
+    }
+}
+
+/// Error returned by [`ChannelStream::reunite`] if the halves are
+/// not from the same `ChannelStream`, which gives them back.
+#[derive(Debug)]
+pub struct ReuniteError(pub ChannelInput, pub ChannelOutput);
+
+impl fmt::Display for ReuniteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("tried to reunite halves that are not from the same ChannelStream")
+    }
+}
+
+impl error::Error for ReuniteError {}
@@ -1,23 +1,32 @@
 use std::{
     ops::Deref,
-    sync::{atomic::AtomicU8, Arc},
+    sync::{atomic::AtomicU8, Arc, Mutex},
 };
 
 use bytes::BytesMut;
+use tokio::sync::OwnedSemaphorePermit;
 
 use super::{ChannelDataArenaArc, SharedData};
-use crate::{request::ChannelClose, Error};
+use crate::{
+    request::{ChannelClose, ChannelEof},
+    Error,
+};
 
 mod channel_state;
+pub use channel_state::ProcessStatus;
 pub(super) use channel_state::{
-    ChannelState, OpenChannelRequestedInner, OpenChannelRes, ProcessStatus,
+    Abandoned, ChannelState, OpenChannelRequestedInner, OpenChannelRes,
 };
 
 mod mpsc_bytes_channel;
 pub(super) use mpsc_bytes_channel::MpscBytesChannel;
 
+mod write_scheduler;
+pub(super) use write_scheduler::WriteScheduler;
+
 mod pending_requests;
-pub(super) use pending_requests::{Completion, PendingRequests};
+pub(super) use pending_requests::PendingRequests;
+pub use pending_requests::RequestOutcome;
 
 mod awaitable_atomic_u64;
 pub(super) use awaitable_atomic_u64::AwaitableAtomicU64;
@@ -28,6 +37,15 @@ pub use channel_input::ChannelInput;
 mod channel_output;
 pub use channel_output::ChannelOutput;
 
+mod open;
+pub(super) use open::{open_direct_tcpip, open_session};
+
+mod session;
+pub use session::{RemoteChild, Session};
+
+mod channel_stream;
+pub use channel_stream::{ChannelStream, ReuniteError};
+
 #[derive(Debug)]
 // Use C repr so that we can decide order of fields here
 // and avoid false sharing if possible.
@@ -62,6 +80,98 @@ pub(super) struct ChannelData {
 
     /// Use u64 to avoid overflow.
     pub(super) sender_window_size: AwaitableAtomicU64,
+
+    /// Eof and close sent to sshd.
+    closing: Mutex<Closing>,
+
+    /// Released once the channel is removed from the arena and
+    /// all references to it are dropped.
+    permit: OwnedSemaphorePermit,
+}
+
+impl ChannelData {
+    /// Wake up every pending operation on this channel and make them
+    /// fail with `err`.
+    pub(super) fn abort(&self, err: &Arc<Error>) {
+        self.state.abort(err);
+        self.pending_requests.abort(err);
+
+        if let Some(rx) = &self.rx {
+            rx.mark_broken();
+        }
+        if let Some(stderr) = &self.stderr {
+            stderr.mark_broken();
+        }
+
+        self.sender_window_size.wake_up();
+    }
+
+    /// Queue eof unless eof or close is already sent.
+    pub(super) fn send_eof(&self, shared_data: &SharedData, channel_id: u32, peer_channel_id: u32) {
+        let mut closing = self.closing.lock().unwrap();
+
+        if closing.eof_sent || closing.close_sent {
+            return;
+        }
+        closing.eof_sent = true;
+
+        // The eof packet is 10 bytes large
+        let mut buffer = BytesMut::with_capacity(10);
+
+        ChannelEof::new(peer_channel_id)
+            .serialize_with_header(&mut buffer, 0)
+            .expect("Serialization should not fail here");
+
+        // Push it while holding the lock, so that it cannot be
+        // queued after close.
+        shared_data
+            .get_write_scheduler()
+            .push_channel_control(channel_id, buffer.freeze());
+    }
+
+    /// Queue close unless it is already sent.
+    pub(super) fn send_close(
+        &self,
+        shared_data: &SharedData,
+        channel_id: u32,
+        peer_channel_id: u32,
+    ) {
+        let mut closing = self.closing.lock().unwrap();
+
+        if closing.close_sent {
+            return;
+        }
+        closing.close_sent = true;
+
+        // The close packet is 10 bytes large
+        let mut buffer = BytesMut::with_capacity(10);
+
+        ChannelClose::new(peer_channel_id)
+            .serialize_with_header(&mut buffer, 0)
+            .expect("Serialization should not fail here");
+
+        shared_data
+            .get_write_scheduler()
+            .push_channel_control(channel_id, buffer.freeze());
+    }
+
+    /// Return true if close is sent, after which no packet can be
+    /// sent to the channel.
+    pub(super) fn is_close_sent(&self) -> bool {
+        self.closing.lock().unwrap().close_sent
+    }
+
+    /// Return true if eof is sent, after which no data can be
+    /// sent to the channel.
+    pub(super) fn is_eof_sent(&self) -> bool {
+        self.closing.lock().unwrap().eof_sent
+    }
+}
+
+#[derive(Debug, Default)]
+struct Closing {
+    eof_sent: bool,
+    close_sent: bool,
 }
 
 /// Reference to the channel.
@@ -80,26 +190,29 @@ struct ChannelRef(Arc<ChannelRefInner>);
 struct ChannelRefInner {
     shared_data: SharedData,
     channel_data: ChannelDataArenaArc,
+    peer_channel_id: u32,
 }
 
 impl ChannelRefInner {
+    /// Id of the channel, used as the recipient channel by sshd.
     fn channel_id(&self) -> u32 {
         ChannelDataArenaArc::slot(&self.channel_data)
     }
 
-    fn send_close(&mut self) {
-        let channel_id = self.channel_id();
-
-        // The close packet is 10 bytes large
-        let mut buffer = BytesMut::with_capacity(10);
+    /// Id of the channel allocated by sshd, used as the recipient
+    /// channel of packets sent.
+    fn peer_channel_id(&self) -> u32 {
+        self.peer_channel_id
+    }
 
-        ChannelClose::new(channel_id)
-            .serialize_with_header(&mut buffer, 0)
-            .expect("Serialization should not fail here");
+    fn send_eof(&self) {
+        self.channel_data
+            .send_eof(&self.shared_data, self.channel_id(), self.peer_channel_id)
+    }
 
-        self.shared_data
-            .get_write_channel()
-            .push_bytes(buffer.freeze());
+    fn send_close(&self) {
+        self.channel_data
+            .send_close(&self.shared_data, self.channel_id(), self.peer_channel_id)
     }
 }
 impl Drop for ChannelRefInner {
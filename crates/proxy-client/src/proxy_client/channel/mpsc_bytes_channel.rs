@@ -1,12 +1,10 @@
 use std::{
-    future::Future,
     mem,
     sync::{Mutex, MutexGuard},
     task::{Context, Poll, Waker},
 };
 
 use bytes::Bytes;
-use futures_util::future::poll_fn;
 
 /// There can be arbitary number of writers and only one reader.
 #[derive(Default, Debug)]
@@ -21,6 +19,9 @@ struct Inner {
     /// Set to true if the reader is dropped so that
     /// no new data will be added.
     reader_dropped: bool,
+
+    /// Set to true if the connection is broken before eof.
+    is_broken: bool,
 }
 
 /// Methods for the read end
@@ -61,16 +62,9 @@ impl MpscBytesChannel {
         Poll::Pending
     }
 
-    /// * `alt_buffer` - it should be an empty buffer and it will be
-    ///   swapped with the internal buffers
-    ///   if the internal buffer is not empty and `is_eof` is false.
-    ///   On eof, it will remain empty.
-    ///   Every `Bytes` in it must not be empty.
-    pub(crate) fn wait_for_data<'a>(
-        &'a self,
-        alt_buffer: &'a mut Vec<Bytes>,
-    ) -> impl Future<Output = ()> + 'a {
-        poll_fn(move |cx| self.poll_for_data(alt_buffer, cx))
+    /// Return true if eof is caused by the connection being broken.
+    pub(crate) fn is_broken(&self) -> bool {
+        self.0.lock().unwrap().is_broken
     }
 
     /// Drop the reader.
@@ -137,6 +131,20 @@ impl MpscBytesChannel {
         Self::wake_up_reader(guard);
     }
 
+    /// Mark eof due to the connection being broken, it is a no-op if
+    /// eof is already received.
+    pub(crate) fn mark_broken(&self) {
+        let mut guard = self.0.lock().unwrap();
+
+        if guard.reader_dropped || guard.is_eof {
+            return;
+        }
+
+        guard.is_eof = true;
+        guard.is_broken = true;
+        Self::wake_up_reader(guard);
+    }
+
     fn wake_up_reader(mut guard: MutexGuard<'_, Inner>) {
         let waker = guard.waker.take();
 
@@ -0,0 +1,262 @@
+use std::{
+    mem,
+    num::NonZeroU32,
+    sync::{atomic::AtomicU8, Arc},
+};
+
+use bytes::BytesMut;
+use serde::Serialize;
+use tokio::sync::OwnedSemaphorePermit;
+
+use super::{
+    Abandoned, ChannelData, ChannelInput, ChannelOutput, ChannelRef, ChannelRefInner, ChannelState,
+    ChannelStream, OpenChannelRes, Session,
+};
+use crate::{
+    proxy_client::{ChannelDataArenaArc, SharedData},
+    request::{self, DirectTcpip, OpenChannel, Request},
+    Error,
+};
+
+/// Open a new channel and wait for the confirmation.
+///
+/// * `create_request` - create the request given the sender channel,
+///   initial window size and max packet size.
+///
+/// Returns the `ChannelRef` and max packet size of sshd.
+///
+/// This function is cancel safe: if the future is dropped after
+/// the request is sent, the channel is closed once it is confirmed.
+async fn open_channel<T, F>(
+    shared_data: &SharedData,
+    permit: OwnedSemaphorePermit,
+    has_stderr: bool,
+    create_request: F,
+) -> Result<(ChannelRef, NonZeroU32), Error>
+where
+    T: Serialize,
+    F: FnOnce(u32, u32, u32) -> Request<OpenChannel<T>>,
+{
+    let config = *shared_data.get_channel_config();
+
+    let channel_data = ChannelData {
+        state: ChannelState::new(config.window_size, config.window_size),
+        pending_requests: Default::default(),
+        receivers_count: AtomicU8::new(if has_stderr { 2 } else { 1 }),
+        rx: Some(Arc::default()),
+        stderr: has_stderr.then(Arc::default),
+        sender_window_size: Default::default(),
+        closing: Default::default(),
+        permit,
+    };
+
+    let channel_data = shared_data.insert_channel_data(channel_data);
+    let channel_id = ChannelDataArenaArc::slot(&channel_data);
+
+    // Before the confirmation, it is our responsibility to remove the
+    // channel from the arena.
+    let remove_channel = || drop(shared_data.remove_channel_data(channel_id));
+
+    let mut buffer = BytesMut::new();
+
+    if let Err(err) = create_request(channel_id, config.window_size, config.max_packet_size)
+        .serialize_with_header(&mut buffer, 0)
+    {
+        remove_channel();
+        return Err(err);
+    }
+
+    shared_data
+        .get_write_scheduler()
+        .push_channel_control(channel_id, buffer.freeze());
+
+    let guard = AbandonGuard {
+        shared_data,
+        channel_data: &channel_data,
+    };
+    let res = channel_data.state.wait_for_confirmation().await;
+    mem::forget(guard);
+
+    match res {
+        Ok(OpenChannelRes::Confirmed {
+            max_packet_size,
+            peer_channel_id,
+        }) => {
+            // Now it is read_task's responsibility to remove the channel.
+            let channel_ref = ChannelRef(Arc::new(ChannelRefInner {
+                shared_data: shared_data.clone(),
+                channel_data,
+                peer_channel_id,
+            }));
+
+            let max_packet_size = NonZeroU32::new(max_packet_size)
+                .ok_or(Error::InvalidResponse(&"max_packet_size of channel is 0"))?;
+
+            Ok((channel_ref, max_packet_size))
+        }
+        Ok(OpenChannelRes::Failed(failure)) => {
+            remove_channel();
+            Err(failure.into())
+        }
+        Err(err) => {
+            remove_channel();
+            Err(err)
+        }
+    }
+}
+
+/// Clean up the channel if `open_channel` is dropped while waiting
+/// for the confirmation.
+struct AbandonGuard<'a> {
+    shared_data: &'a SharedData,
+    channel_data: &'a ChannelDataArenaArc,
+}
+
+impl Drop for AbandonGuard<'_> {
+    fn drop(&mut self) {
+        let channel_id = ChannelDataArenaArc::slot(self.channel_data);
+
+        match self.channel_data.state.abandon() {
+            Abandoned::Pending => (),
+            Abandoned::Confirmed { peer_channel_id } => {
+                self.channel_data
+                    .send_close(self.shared_data, channel_id, peer_channel_id)
+            }
+            Abandoned::Failed => drop(self.shared_data.remove_channel_data(channel_id)),
+        }
+    }
+}
+
+pub(in crate::proxy_client) async fn open_session(
+    shared_data: &SharedData,
+    permit: OwnedSemaphorePermit,
+) -> Result<Session, Error> {
+    let (channel_ref, max_packet_size) =
+        open_channel(shared_data, permit, true, request::Session::new).await?;
+
+    Ok(Session::new(channel_ref, max_packet_size))
+}
+
+pub(in crate::proxy_client) async fn open_direct_tcpip(
+    shared_data: &SharedData,
+    permit: OwnedSemaphorePermit,
+    host: (&str, u32),
+    originator: (&str, u32),
+) -> Result<ChannelStream, Error> {
+    let (channel_ref, max_packet_size) = open_channel(
+        shared_data,
+        permit,
+        false,
+        |sender_channel, initial_windows_size, max_packet_size| {
+            DirectTcpip::new(
+                sender_channel,
+                initial_windows_size,
+                max_packet_size,
+                host,
+                originator,
+            )
+        },
+    )
+    .await?;
+
+    let rx = channel_ref.channel_data.rx.clone().unwrap();
+
+    Ok(ChannelStream::new(
+        ChannelInput::new(channel_ref.clone(), max_packet_size),
+        ChannelOutput::new(channel_ref, rx),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use tokio::io::{split, AsyncWriteExt, DuplexStream, ReadHalf};
+
+    use crate::{constants::*, proxy_client::test_utils::*, ProxyClient};
+
+    fn client_with_one_channel() -> (ProxyClient, DuplexStream) {
+        client(ProxyClient::builder().max_channels(NonZeroUsize::new(1).unwrap()))
+    }
+
+    /// Drop `open_session` once the request is sent, return the sender
+    /// channel of the request.
+    async fn cancel_open_session(
+        client: &ProxyClient,
+        peer_rx: &mut ReadHalf<DuplexStream>,
+    ) -> u32 {
+        let open = client.open_session();
+        tokio::pin!(open);
+
+        tokio::select! {
+            res = &mut open => panic!("Unexpected response {:?}", res),
+            packet = read_packet(peer_rx) => open_channel_sender(&packet),
+        }
+    }
+
+    async fn assert_can_open_session(client: &ProxyClient, peer: DuplexStream) {
+        let (mut peer_rx, mut peer_tx) = split(peer);
+
+        let peer = async move {
+            let channel_id = open_channel_sender(&read_packet(&mut peer_rx).await);
+
+            peer_tx
+                .write_all(&open_confirmation(channel_id, 1))
+                .await
+                .unwrap();
+
+            (peer_rx, peer_tx)
+        };
+
+        let (session, _peer) = tokio::join!(client.open_session(), peer);
+        session.unwrap();
+
+        assert_eq!(client.channel_count(), 1);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_cancel_open_session_confirmed() {
+        let (client, peer) = client_with_one_channel();
+        let (mut peer_rx, mut peer_tx) = split(peer);
+
+        let channel_id = cancel_open_session(&client, &mut peer_rx).await;
+        assert_eq!(client.channel_count(), 1);
+
+        peer_tx
+            .write_all(&open_confirmation(channel_id, 0))
+            .await
+            .unwrap();
+
+        // The abandoned channel is closed once confirmed
+        let close = read_packet(&mut peer_rx).await;
+        assert_eq!(close[1], SSH_MSG_CHANNEL_CLOSE);
+        assert_eq!(recipient_channel(&close), 0);
+
+        peer_tx
+            .write_all(&packet(SSH_MSG_CHANNEL_CLOSE, &channel_id.to_be_bytes()))
+            .await
+            .unwrap();
+
+        assert_can_open_session(&client, peer_rx.unsplit(peer_tx)).await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_cancel_open_session_failed() {
+        let (client, peer) = client_with_one_channel();
+        let (mut peer_rx, mut peer_tx) = split(peer);
+
+        let channel_id = cancel_open_session(&client, &mut peer_rx).await;
+
+        let mut failure = channel_id.to_be_bytes().to_vec();
+        // Reason code, empty description and language tag
+        for int in [4_u32, 0, 0] {
+            failure.extend(int.to_be_bytes());
+        }
+        peer_tx
+            .write_all(&packet(SSH_MSG_CHANNEL_OPEN_FAILURE, &failure))
+            .await
+            .unwrap();
+
+        assert_can_open_session(&client, peer_rx.unsplit(peer_tx)).await;
+    }
+}
@@ -3,10 +3,12 @@ use std::{
     mem,
     num::NonZeroUsize,
     pin::Pin,
-    sync::{Mutex, MutexGuard},
+    sync::{Arc, Mutex, MutexGuard},
     task::{Context, Poll, Waker},
 };
 
+use crate::Error;
+
 /// Suitable for spsc.
 ///
 /// Send one or multiple requests and use
@@ -23,18 +25,29 @@ enum Inner {
         /// usize is enough since all requests have to be buffered in memory
         /// before sending.
         pending_requests: NonZeroUsize,
+        /// Outcomes received so far, in the order the requests are sent.
+        outcomes: Vec<RequestOutcome>,
         waker: Option<Waker>,
     },
 
-    Done(Completion),
+    Done(Vec<RequestOutcome>),
+
+    /// The connection is broken.
+    Aborted(Arc<Error>),
 }
 
-#[derive(Copy, Clone, Debug)]
-pub(crate) enum Completion {
-    /// All requests succeeded
+/// Outcome of a channel request, as replied by sshd.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RequestOutcome {
     Success,
-    /// Some requests failed
-    Failed,
+    Failure,
+}
+
+impl RequestOutcome {
+    /// Return true if the request succeeded.
+    pub fn is_success(self) -> bool {
+        matches!(self, RequestOutcome::Success)
+    }
 }
 
 impl PendingRequests {
@@ -42,7 +55,7 @@ impl PendingRequests {
     /// are flushed.
     ///
     /// Once start_new_requests, wait_for_completion must be called.
-    pub(crate) async fn start_new_requests<F>(&self, requests: NonZeroUsize) {
+    pub(crate) async fn start_new_requests(&self, requests: NonZeroUsize) {
         struct WaitForPrevCompletion<'a>(&'a PendingRequests);
 
         impl<'a> Future for WaitForPrevCompletion<'a> {
@@ -52,7 +65,7 @@ impl PendingRequests {
                 let mut guard = self.0 .0.lock().unwrap();
 
                 match &mut *guard {
-                    Inner::Done(..) | Inner::NotStarted => Poll::Ready(guard),
+                    Inner::Done(..) | Inner::NotStarted | Inner::Aborted(..) => Poll::Ready(guard),
                     Inner::Waiting { waker, .. } => {
                         let prev_waker = mem::replace(waker, Some(cx.waker().clone()));
 
@@ -69,12 +82,16 @@ impl PendingRequests {
 
         let mut guard = WaitForPrevCompletion(self).await;
 
+        if let Inner::Aborted(..) = &*guard {
+            // Leave it as is so that `wait_for_completion` returns the error.
+            return;
+        }
+
         debug_assert!(matches!(&*guard, Inner::NotStarted | Inner::Done { .. }));
 
-        // This overwrites should be simply memcpy.
-        // Dropping the old value should be zero-cost.
         *guard = Inner::Waiting {
             pending_requests: requests,
+            outcomes: Vec::with_capacity(requests.get()),
             waker: None,
         };
     }
@@ -84,17 +101,24 @@ impl PendingRequests {
     ///
     /// This function must be called after
     /// [`PendingRequests::start_new_requests`] is called.
-    pub(crate) fn wait_for_completion(&self) -> impl Future<Output = Completion> + '_ {
+    ///
+    /// Return outcomes of the requests in the order they are sent, or
+    /// [`Error::ConnectionBroken`] if the connection is broken
+    /// before all responses are received.
+    pub(crate) fn wait_for_completion(
+        &self,
+    ) -> impl Future<Output = Result<Vec<RequestOutcome>, Error>> + '_ {
         struct WaitForCompletion<'a>(&'a PendingRequests);
 
         impl Future for WaitForCompletion<'_> {
-            type Output = Completion;
+            type Output = Result<Vec<RequestOutcome>, Error>;
 
             fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
                 let mut guard = self.0 .0.lock().unwrap();
 
                 match &mut *guard {
-                    Inner::Done(completion) => Poll::Ready(*completion),
+                    Inner::Done(outcomes) => Poll::Ready(Ok(mem::take(outcomes))),
+                    Inner::Aborted(err) => Poll::Ready(Err(Error::ConnectionBroken(err.clone()))),
                     Inner::Waiting { waker, .. } => {
                         let prev_waker = mem::replace(waker, Some(cx.waker().clone()));
 
@@ -115,29 +139,139 @@ impl PendingRequests {
         WaitForCompletion(self)
     }
 
-    /// Retrieve number of pending requests.
-    pub(crate) fn retrieve_pending_requests(&self) -> Option<NonZeroUsize> {
+    /// Report outcome of the oldest request not replied yet.
+    ///
+    /// Return [`Error::UnexpectedRequestResponse`] if there is no
+    /// pending request.
+    pub(crate) fn report_request_outcome(&self, outcome: RequestOutcome) -> Result<(), Error> {
+        let mut guard = self.0.lock().unwrap();
+
+        let (pending_requests, outcomes) = match &mut *guard {
+            Inner::Waiting {
+                pending_requests,
+                outcomes,
+                ..
+            } => (*pending_requests, outcomes),
+            // The read task might still be running if only the
+            // write task failed.
+            Inner::Aborted(..) => return Ok(()),
+            Inner::NotStarted | Inner::Done(..) => return Err(Error::UnexpectedRequestResponse),
+        };
+
+        outcomes.push(outcome);
+
+        if outcomes.len() < pending_requests.get() {
+            return Ok(());
+        }
+
+        // All requests are replied
+        let outcomes = mem::take(outcomes);
+        let prev_state = mem::replace(&mut *guard, Inner::Done(outcomes));
+
+        // Release mutex
+        drop(guard);
+
         if let Inner::Waiting {
-            pending_requests, ..
-        } = &*self.0.lock().unwrap()
+            waker: Some(waker), ..
+        } = prev_state
         {
-            Some(*pending_requests)
-        } else {
-            None
+            waker.wake();
         }
+
+        Ok(())
     }
 
-    /// Report completion of all requests.
-    pub(crate) fn report_request_completion(&self, completion: Completion) {
-        let prev_state = mem::replace(&mut *self.0.lock().unwrap(), Inner::Done(completion));
+    /// Called when the connection is broken, so that
+    /// `wait_for_completion` returns `err`.
+    pub(crate) fn abort(&self, err: &Arc<Error>) {
+        let prev_state = mem::replace(&mut *self.0.lock().unwrap(), Inner::Aborted(err.clone()));
 
-        match prev_state {
-            Inner::Waiting { waker, .. } => {
-                if let Some(waker) = waker {
-                    waker.wake()
-                }
+        if let Inner::Waiting {
+            waker: Some(waker), ..
+        } = prev_state
+        {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::FutureExt;
+    use tokio::task::yield_now;
+
+    use RequestOutcome::*;
+
+    fn requests(n: usize) -> NonZeroUsize {
+        NonZeroUsize::new(n).unwrap()
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_outcomes_in_order() {
+        let pending_requests = PendingRequests::default();
+
+        pending_requests.start_new_requests(requests(3)).await;
+
+        pending_requests.report_request_outcome(Success).unwrap();
+        pending_requests.report_request_outcome(Failure).unwrap();
+
+        let completion = pending_requests.wait_for_completion();
+        tokio::pin!(completion);
+        assert!((&mut completion).now_or_never().is_none());
+
+        pending_requests.report_request_outcome(Success).unwrap();
+
+        assert_eq!(completion.await.unwrap(), [Success, Failure, Success]);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_unexpected_response() {
+        let pending_requests = PendingRequests::default();
+
+        assert!(matches!(
+            pending_requests.report_request_outcome(Success),
+            Err(Error::UnexpectedRequestResponse)
+        ));
+
+        pending_requests.start_new_requests(requests(1)).await;
+        pending_requests.report_request_outcome(Failure).unwrap();
+
+        assert!(matches!(
+            pending_requests.report_request_outcome(Success),
+            Err(Error::UnexpectedRequestResponse)
+        ));
+        assert_eq!(
+            pending_requests.wait_for_completion().await.unwrap(),
+            [Failure]
+        );
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_abort_wakes_up_waiter() {
+        let pending_requests = PendingRequests::default();
+
+        pending_requests.start_new_requests(requests(2)).await;
+        pending_requests.report_request_outcome(Success).unwrap();
+
+        let abort = async {
+            // Let the waiter register its waker first
+            yield_now().await;
+
+            pending_requests.abort(&Arc::new(Error::ChannelClosed));
+        };
+
+        let (res, ()) = tokio::join!(pending_requests.wait_for_completion(), abort);
+
+        match res {
+            Err(Error::ConnectionBroken(err)) => {
+                assert!(matches!(*err, Error::ChannelClosed), "{:?}", err)
             }
-            _ => panic!("Invalid state, expected `Waiting`!"),
+            res => panic!("Unexpected result {:?}", res),
         }
+
+        // Responses still received after the write task fails are ignored
+        pending_requests.report_request_outcome(Success).unwrap();
     }
 }
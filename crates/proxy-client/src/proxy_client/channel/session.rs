@@ -0,0 +1,194 @@
+use std::{
+    borrow::Cow,
+    num::{NonZeroU32, NonZeroUsize},
+};
+
+use bytes::BytesMut;
+use serde::Serialize;
+
+use super::{ChannelInput, ChannelOutput, ChannelRef, ProcessStatus, RequestOutcome};
+use crate::{
+    request::{ChannelRequest, ExecCmd, PassEnv, Request, RequestShell, RequestSubsystem},
+    Error, NonZeroByteSlice,
+};
+
+/// A session channel, on which a remote process can be started.
+///
+/// The channel is closed on drop.
+#[derive(Debug)]
+pub struct Session {
+    channel_ref: ChannelRef,
+    max_packet_size: NonZeroU32,
+}
+
+impl Session {
+    pub(super) fn new(channel_ref: ChannelRef, max_packet_size: NonZeroU32) -> Self {
+        Self {
+            channel_ref,
+            max_packet_size,
+        }
+    }
+
+    /// Id of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_ref.channel_id()
+    }
+
+    /// Execute `cmd` with environment variables in `env` set.
+    ///
+    /// Return the outcome of setting every variable, in the same order
+    /// as `env`: sshd rejects variables not listed in `AcceptEnv`, which
+    /// does not prevent `cmd` from being executed.
+    ///
+    /// Fails with [`Error::ChannelRequestFailed`] if sshd refuses
+    /// to execute `cmd`.
+    pub async fn exec(
+        self,
+        env: &[(&str, &NonZeroByteSlice)],
+        cmd: &NonZeroByteSlice,
+    ) -> Result<(RemoteChild, Vec<RequestOutcome>), Error> {
+        let request = ExecCmd::new(self.channel_ref.peer_channel_id(), Cow::Borrowed(cmd));
+        self.start(env, request).await
+    }
+
+    /// Start subsystem `subsystem`, e.g. sftp, with environment
+    /// variables in `env` set.
+    ///
+    /// Return the outcome of setting every variable, same as
+    /// [`Session::exec`].
+    ///
+    /// Fails with [`Error::ChannelRequestFailed`] if sshd refuses
+    /// to start `subsystem`.
+    pub async fn subsystem(
+        self,
+        env: &[(&str, &NonZeroByteSlice)],
+        subsystem: &NonZeroByteSlice,
+    ) -> Result<(RemoteChild, Vec<RequestOutcome>), Error> {
+        let request =
+            RequestSubsystem::new(self.channel_ref.peer_channel_id(), Cow::Borrowed(subsystem));
+        self.start(env, request).await
+    }
+
+    /// Start the login shell of the user with environment variables
+    /// in `env` set.
+    ///
+    /// Return the outcome of setting every variable, same as
+    /// [`Session::exec`].
+    ///
+    /// Fails with [`Error::ChannelRequestFailed`] if sshd refuses
+    /// to start the shell.
+    pub async fn shell(
+        self,
+        env: &[(&str, &NonZeroByteSlice)],
+    ) -> Result<(RemoteChild, Vec<RequestOutcome>), Error> {
+        let request = RequestShell::new(self.channel_ref.peer_channel_id());
+        self.start(env, request).await
+    }
+
+    async fn start<T: Serialize>(
+        self,
+        env: &[(&str, &NonZeroByteSlice)],
+        request: Request<ChannelRequest<T>>,
+    ) -> Result<(RemoteChild, Vec<RequestOutcome>), Error> {
+        let channel_ref = &self.channel_ref;
+        let peer_channel_id = channel_ref.peer_channel_id();
+
+        // Pipeline all requests
+        let mut buffer = BytesMut::new();
+
+        for (name, value) in env {
+            PassEnv::new(peer_channel_id, Cow::Borrowed(*name), Cow::Borrowed(*value))
+                .serialize_with_header(&mut buffer, 0)?;
+        }
+        request.serialize_with_header(&mut buffer, 0)?;
+
+        let pending_requests = &channel_ref.channel_data.pending_requests;
+
+        pending_requests
+            .start_new_requests(NonZeroUsize::new(env.len() + 1).unwrap())
+            .await;
+
+        channel_ref
+            .shared_data
+            .get_write_scheduler()
+            .push_channel_control(channel_ref.channel_id(), buffer.freeze());
+
+        let mut outcomes = pending_requests.wait_for_completion().await?;
+
+        // The last one is the request starting the process.
+        match outcomes.pop().expect("There is at least one request") {
+            RequestOutcome::Success => Ok((
+                RemoteChild::new(self.channel_ref, self.max_packet_size),
+                outcomes,
+            )),
+            RequestOutcome::Failure => Err(Error::ChannelRequestFailed),
+        }
+    }
+}
+
+/// Remote process started on a [`Session`].
+///
+/// The channel is closed once it and all of its stdin/stdout/stderr
+/// are dropped.
+#[derive(Debug)]
+pub struct RemoteChild {
+    channel_ref: ChannelRef,
+
+    /// Cached result of [`RemoteChild::wait`].
+    status: Option<Option<ProcessStatus>>,
+
+    pub stdin: Option<ChannelInput>,
+    pub stdout: Option<ChannelOutput>,
+    pub stderr: Option<ChannelOutput>,
+}
+
+impl RemoteChild {
+    fn new(channel_ref: ChannelRef, max_packet_size: NonZeroU32) -> Self {
+        let channel_data = &channel_ref.channel_data;
+
+        let stdout = ChannelOutput::new(channel_ref.clone(), channel_data.rx.clone().unwrap());
+        let stderr = ChannelOutput::new(channel_ref.clone(), channel_data.stderr.clone().unwrap());
+        let stdin = ChannelInput::new(channel_ref.clone(), max_packet_size);
+
+        Self {
+            channel_ref,
+            status: None,
+            stdin: Some(stdin),
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+        }
+    }
+
+    /// Id of the channel.
+    pub fn channel_id(&self) -> u32 {
+        self.channel_ref.channel_id()
+    }
+
+    /// Drop stdin, so that eof is sent, then wait for the process to exit.
+    ///
+    /// Return `None` if the channel is closed without reporting
+    /// the exit status.
+    ///
+    /// stdout and stderr are kept open, so that the process would not
+    /// block on writing to them.
+    ///
+    /// This function is cancel safe.
+    pub async fn wait(&mut self) -> Result<Option<ProcessStatus>, Error> {
+        drop(self.stdin.take());
+
+        if let Some(status) = &self.status {
+            return Ok(status.clone());
+        }
+
+        let status = self
+            .channel_ref
+            .channel_data
+            .state
+            .wait_for_process_exit()
+            .await?;
+
+        self.status = Some(status.clone());
+
+        Ok(status)
+    }
+}
@@ -0,0 +1,370 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    mem,
+    num::NonZeroU8,
+    sync::{Mutex, MutexGuard},
+    task::{Context, Poll, Waker},
+};
+
+use bytes::Bytes;
+use futures_util::future::poll_fn;
+use integer_hasher::IntMap;
+
+/// Number of bytes added to the deficit of a channel with priority 1
+/// in every round.
+///
+/// It is the default max packet size of openssh, so that a packet
+/// can usually be sent in one round.
+const QUANTUM: usize = 32 * 1024;
+
+/// Outbound queue shared by all channels, with arbitary number of writers
+/// and only one reader (the write task).
+///
+/// Packets are scheduled as follows:
+///  - Control packets not bound to any channel data (e.g. window adjust)
+///    are always written first.
+///  - Data packets are queued per channel and scheduled using deficit
+///    round-robin, weighted by the priority of the channel, so that
+///    a bulk transfer on one channel cannot starve the others.
+///  - Eof and close are queued after the data of their own channel,
+///    since no data can be sent after them, but they are not charged
+///    against the deficit so they never wait for more than one round.
+#[derive(Default, Debug)]
+pub(crate) struct WriteScheduler(Mutex<Inner>);
+
+#[derive(Default, Debug)]
+struct Inner {
+    is_eof: bool,
+    waker: Option<Waker>,
+
+    control: Vec<Bytes>,
+
+    channels: IntMap<u32, ChannelQueue>,
+
+    /// Channels with packets queued, in round-robin order.
+    active: VecDeque<u32>,
+}
+
+#[derive(Debug)]
+struct ChannelQueue {
+    /// Every `Bytes` in it must not be empty.
+    segments: VecDeque<Bytes>,
+    packets: VecDeque<Packet>,
+    deficit: usize,
+    priority: NonZeroU8,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Packet {
+    /// Number of segments in this packet.
+    segments: usize,
+    len: usize,
+    /// Whether it is charged against the deficit.
+    is_data: bool,
+}
+
+impl Inner {
+    fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.active.is_empty()
+    }
+
+    /// Run one round of deficit round-robin and move the packets
+    /// scheduled into `buffer`.
+    fn schedule_round(&mut self, buffer: &mut Vec<Bytes>) {
+        for _ in 0..self.active.len() {
+            let channel_id = self.active.pop_front().unwrap();
+            let queue = self.channels.get_mut(&channel_id).unwrap();
+
+            queue.deficit += QUANTUM * usize::from(queue.priority.get());
+
+            while let Some(packet) = queue.packets.front().copied() {
+                if packet.is_data {
+                    if packet.len > queue.deficit {
+                        break;
+                    }
+                    queue.deficit -= packet.len;
+                }
+
+                queue.packets.pop_front();
+                buffer.extend(queue.segments.drain(..packet.segments));
+            }
+
+            if queue.packets.is_empty() {
+                // Idle channels do not accumulate deficit.
+                self.channels.remove(&channel_id);
+            } else {
+                self.active.push_back(channel_id);
+            }
+        }
+    }
+
+    fn push_packet<It>(
+        &mut self,
+        channel_id: u32,
+        priority: Option<NonZeroU8>,
+        is_data: bool,
+        iter: It,
+    ) where
+        It: IntoIterator<Item = Bytes>,
+    {
+        let active = &mut self.active;
+
+        let queue = self.channels.entry(channel_id).or_insert_with(|| {
+            active.push_back(channel_id);
+
+            ChannelQueue {
+                segments: VecDeque::new(),
+                packets: VecDeque::new(),
+                deficit: 0,
+                priority: NonZeroU8::new(1).unwrap(),
+            }
+        });
+
+        if let Some(priority) = priority {
+            queue.priority = priority;
+        }
+
+        let before = queue.segments.len();
+
+        let mut len = 0;
+        queue.segments.extend(
+            iter.into_iter()
+                .filter(|bytes| !bytes.is_empty())
+                .inspect(|bytes| len += bytes.len()),
+        );
+
+        let segments = queue.segments.len() - before;
+
+        if segments != 0 {
+            queue.packets.push_back(Packet {
+                segments,
+                len,
+                is_data,
+            });
+        } else if queue.packets.is_empty() {
+            // Nothing is queued
+            self.channels.remove(&channel_id);
+            self.active.retain(|id| *id != channel_id);
+        }
+    }
+}
+
+/// Methods for the read end
+impl WriteScheduler {
+    /// * `alt_buffer` - it should be an empty buffer and it will be
+    ///   filled with the packets scheduled if there is any.
+    ///   On eof, it will remain empty.
+    ///   Every `Bytes` in it must not be empty.
+    pub(crate) fn poll_for_data(
+        &self,
+        alt_buffer: &mut Vec<Bytes>,
+        cx: &mut Context<'_>,
+    ) -> Poll<()> {
+        alt_buffer.clear();
+
+        let mut guard = self.0.lock().unwrap();
+
+        if !guard.is_empty() {
+            mem::swap(&mut guard.control, alt_buffer);
+
+            // Deficits might be too small to schedule any packet
+            // in the first round.
+            loop {
+                guard.schedule_round(alt_buffer);
+
+                if !alt_buffer.is_empty() || guard.active.is_empty() {
+                    break;
+                }
+            }
+
+            return Poll::Ready(());
+        }
+
+        if guard.is_eof {
+            return Poll::Ready(());
+        }
+
+        let prev_waker = guard.waker.replace(cx.waker().clone());
+
+        // Release the lock
+        drop(guard);
+
+        // Drop prev_waker here to reduce the critical section.
+        drop(prev_waker);
+
+        Poll::Pending
+    }
+
+    /// * `alt_buffer` - it should be an empty buffer and it will be
+    ///   filled with the packets scheduled if there is any.
+    ///   On eof, it will remain empty.
+    ///   Every `Bytes` in it must not be empty.
+    pub(crate) fn wait_for_data<'a>(
+        &'a self,
+        alt_buffer: &'a mut Vec<Bytes>,
+    ) -> impl Future<Output = ()> + 'a {
+        poll_fn(move |cx| self.poll_for_data(alt_buffer, cx))
+    }
+}
+
+/// Methods for the write end
+impl WriteScheduler {
+    /// Queue a control packet that is written before any queued data,
+    /// e.g. window adjust.
+    pub(crate) fn push_control(&self, packet: Bytes) {
+        if !packet.is_empty() {
+            let mut guard = self.0.lock().unwrap();
+            guard.control.push(packet);
+            Self::wake_up_reader(guard);
+        }
+    }
+
+    /// Queue a control packet (eof or close) of channel `channel_id`
+    /// after the data already queued for it.
+    pub(crate) fn push_channel_control(&self, channel_id: u32, packet: Bytes) {
+        let mut guard = self.0.lock().unwrap();
+        guard.push_packet(channel_id, None, false, Some(packet));
+        Self::wake_up_reader(guard);
+    }
+
+    /// Queue a data packet of channel `channel_id`, consisting of every
+    /// `Bytes` in `iter`.
+    ///
+    /// * `priority` - weight of the channel in scheduling.
+    pub(crate) fn push_data<It>(&self, channel_id: u32, priority: NonZeroU8, iter: It)
+    where
+        It: IntoIterator<Item = Bytes>,
+    {
+        let mut guard = self.0.lock().unwrap();
+        guard.push_packet(channel_id, Some(priority), true, iter);
+        Self::wake_up_reader(guard);
+    }
+
+    /// You must not push any packet after this call.
+    pub(crate) fn mark_eof(&self) {
+        let mut guard = self.0.lock().unwrap();
+
+        guard.is_eof = true;
+        Self::wake_up_reader(guard);
+    }
+
+    fn wake_up_reader(mut guard: MutexGuard<'_, Inner>) {
+        if guard.is_empty() && !guard.is_eof {
+            return;
+        }
+
+        let waker = guard.waker.take();
+
+        // Release the lock
+        drop(guard);
+
+        // Wake after release to reduce critical section
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::task::noop_waker_ref;
+
+    fn packet(channel_id: u8, len: usize) -> Bytes {
+        Bytes::from(vec![channel_id; len])
+    }
+
+    fn priority(priority: u8) -> NonZeroU8 {
+        NonZeroU8::new(priority).unwrap()
+    }
+
+    /// Return the packets scheduled in one call to `poll_for_data`.
+    fn poll(scheduler: &WriteScheduler) -> Vec<Bytes> {
+        let mut buffer = Vec::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+
+        assert!(scheduler.poll_for_data(&mut buffer, &mut cx).is_ready());
+        buffer
+    }
+
+    #[test]
+    fn test_priority_weighted_shares() {
+        let scheduler = WriteScheduler::default();
+
+        for _ in 0..8 {
+            scheduler.push_data(1, priority(1), Some(packet(1, QUANTUM)));
+            scheduler.push_data(2, priority(3), Some(packet(2, QUANTUM)));
+        }
+
+        let count = |buffer: &[Bytes], channel_id| {
+            buffer.iter().filter(|bytes| bytes[0] == channel_id).count()
+        };
+
+        let buffer = poll(&scheduler);
+        assert_eq!(count(&buffer, 1), 1);
+        assert_eq!(count(&buffer, 2), 3);
+
+        let buffer = poll(&scheduler);
+        assert_eq!(count(&buffer, 1), 1);
+        assert_eq!(count(&buffer, 2), 3);
+    }
+
+    #[test]
+    fn test_large_packet_accumulates_deficit() {
+        let scheduler = WriteScheduler::default();
+
+        scheduler.push_data(1, priority(1), Some(packet(1, 3 * QUANTUM)));
+
+        // Multiple rounds are run in one call until a packet is scheduled
+        assert_eq!(poll(&scheduler), [packet(1, 3 * QUANTUM)]);
+    }
+
+    #[test]
+    fn test_control_first() {
+        let scheduler = WriteScheduler::default();
+
+        scheduler.push_data(1, priority(1), Some(packet(1, 10)));
+        scheduler.push_channel_control(2, packet(2, 10));
+        scheduler.push_control(packet(0, 10));
+
+        let buffer = poll(&scheduler);
+        assert_eq!(buffer[0], packet(0, 10));
+        assert_eq!(buffer.len(), 3);
+    }
+
+    #[test]
+    fn test_channel_control_after_data() {
+        let scheduler = WriteScheduler::default();
+
+        for _ in 0..3 {
+            scheduler.push_data(1, priority(1), Some(packet(1, QUANTUM)));
+        }
+        // Eof
+        scheduler.push_channel_control(1, packet(0, 10));
+
+        assert_eq!(poll(&scheduler), [packet(1, QUANTUM)]);
+        assert_eq!(poll(&scheduler), [packet(1, QUANTUM)]);
+
+        // Eof is not charged against the deficit, so it is scheduled
+        // in the same round as the last data.
+        assert_eq!(poll(&scheduler), [packet(1, QUANTUM), packet(0, 10)]);
+    }
+
+    #[test]
+    fn test_eof() {
+        let scheduler = WriteScheduler::default();
+
+        let mut buffer = Vec::new();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(scheduler.poll_for_data(&mut buffer, &mut cx).is_pending());
+
+        scheduler.push_control(packet(0, 10));
+        scheduler.mark_eof();
+
+        // Packets queued before eof are still returned
+        assert_eq!(poll(&scheduler), [packet(0, 10)]);
+        assert!(poll(&scheduler).is_empty());
+    }
+}
@@ -1,52 +1,232 @@
-use std::num::NonZeroUsize;
+use std::{
+    num::{NonZeroU32, NonZeroUsize},
+    sync::Arc,
+};
 
 use openssh_proxy_client_error::Error;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
+    sync::oneshot,
     task::JoinHandle,
+    time::{timeout_at, Instant},
 };
 
 mod channel;
+pub use channel::{
+    ChannelInput, ChannelOutput, ChannelStream, ProcessStatus, RemoteChild, RequestOutcome,
+    ReuniteError, Session,
+};
 
 mod shared_data;
-use shared_data::{ChannelDataArenaArc, SharedData};
+use shared_data::{
+    ChannelConfig, ChannelDataArenaArc, SharedData, DEFAULT_MAX_PACKET_SIZE, DEFAULT_WINDOW_SIZE,
+};
 
 mod read_task;
-use read_task::create_read_task;
+use read_task::{create_read_task, default_max_incoming_packet_size};
 
 mod write_task;
 use write_task::create_write_task;
+pub use write_task::{WriteCoalescing, WriteStats};
+
+mod builder;
+pub use builder::{DriverFuture, ProxyClientBuilder};
+
+#[cfg(test)]
+mod test_utils;
+
+type TaskResult = Result<(), Arc<Error>>;
+
+#[derive(Debug)]
+enum TaskHandle {
+    /// Spawned using `tokio::spawn`.
+    Tokio(JoinHandle<TaskResult>),
+    /// Driven by a [`DriverFuture`].
+    Driver(oneshot::Receiver<TaskResult>),
+}
+
+impl TaskHandle {
+    async fn wait(self) -> Result<TaskResult, Error> {
+        match self {
+            TaskHandle::Tokio(handle) => Ok(handle.await?),
+            TaskHandle::Driver(receiver) => receiver.await.map_err(|_| Error::DriverDropped),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct ProxyClient {
     shared_data: SharedData,
-    read_task: JoinHandle<Result<(), Error>>,
-    write_task: JoinHandle<Result<(), Error>>,
+    read_task: TaskHandle,
+    write_task: TaskHandle,
 }
 
 impl ProxyClient {
     /// * `reusable_io_slice_cap` - determines how many `Bytes` can be sent
     ///   in one syscall to reduce overhead.
+    ///
+    /// Use [`ProxyClient::builder`] for more options.
     pub fn new<R, W>(rx: R, tx: W, reusable_io_slice_cap: NonZeroUsize) -> Self
     where
         R: AsyncRead + Send + 'static,
         W: AsyncWrite + Send + 'static,
     {
-        let shared_data = SharedData::default();
+        Self::builder()
+            .reusable_io_slice_cap(reusable_io_slice_cap)
+            .build(rx, tx)
+    }
+
+    /// Shortcut for `ProxyClientBuilder::new()`.
+    pub fn builder() -> ProxyClientBuilder {
+        ProxyClientBuilder::new()
+    }
+
+    /// Open a session channel, on which a remote process can be started.
+    ///
+    /// If the number of channels reached the limit, it waits until
+    /// a channel is freed.
+    ///
+    /// This function is cancel safe: if it is dropped after the request
+    /// is sent, the channel is closed once sshd confirms it.
+    pub async fn open_session(&self) -> Result<Session, Error> {
+        let permit = self.shared_data.get_channel_limit().acquire().await;
+        channel::open_session(&self.shared_data, permit).await
+    }
+
+    /// Same as [`ProxyClient::open_session`], except that it fails with
+    /// [`Error::ChannelLimitReached`] immediately if the number of
+    /// channels reached the limit.
+    pub async fn try_open_session(&self) -> Result<Session, Error> {
+        let permit = self.shared_data.get_channel_limit().try_acquire()?;
+        channel::open_session(&self.shared_data, permit).await
+    }
+
+    /// Open a channel forwarding to `host` on behalf of `originator`,
+    /// which are pairs of address and port.
+    ///
+    /// Use [`ChannelStream::into_split`] to read and write concurrently
+    /// from different tasks.
+    ///
+    /// If the number of channels reached the limit, it waits until
+    /// a channel is freed.
+    ///
+    /// This function is cancel safe: if it is dropped after the request
+    /// is sent, the channel is closed once sshd confirms it.
+    pub async fn open_direct_tcpip(
+        &self,
+        host: (&str, u32),
+        originator: (&str, u32),
+    ) -> Result<ChannelStream, Error> {
+        let permit = self.shared_data.get_channel_limit().acquire().await;
+        channel::open_direct_tcpip(&self.shared_data, permit, host, originator).await
+    }
+
+    /// Same as [`ProxyClient::open_direct_tcpip`], except that it fails
+    /// with [`Error::ChannelLimitReached`] immediately if the number of
+    /// channels reached the limit.
+    pub async fn try_open_direct_tcpip(
+        &self,
+        host: (&str, u32),
+        originator: (&str, u32),
+    ) -> Result<ChannelStream, Error> {
+        let permit = self.shared_data.get_channel_limit().try_acquire()?;
+        channel::open_direct_tcpip(&self.shared_data, permit, host, originator).await
+    }
+
+    /// Number of channels alive, including the ones being opened.
+    pub fn channel_count(&self) -> usize {
+        self.shared_data.get_channel_limit().channel_count()
+    }
+
+    /// Maximum number of channels alive, `None` if unlimited.
+    pub fn max_channels(&self) -> Option<NonZeroUsize> {
+        self.shared_data.get_channel_limit().max_channels()
+    }
+
+    /// Return statistics of writes done so far.
+    pub fn write_stats(&self) -> WriteStats {
+        self.shared_data.get_write_counters().get()
+    }
+
+    /// Maximum size of incoming packets, excluding the 4-byte length
+    /// field, defaults to 35000 bytes (the minimum RFC 4253 requires
+    /// every implementation to support) plus the max packet size
+    /// advertised for every channel.
+    pub fn max_incoming_packet_size(&self) -> NonZeroU32 {
+        NonZeroU32::new(self.shared_data.get_max_incoming_packet_size().get()).unwrap()
+    }
 
-        Self {
-            write_task: create_write_task(tx, shared_data.clone(), reusable_io_slice_cap),
-            read_task: create_read_task(rx, shared_data.clone()),
-            shared_data,
+    /// Set the maximum size of incoming packets.
+    ///
+    /// Once a packet larger than it is received, the connection
+    /// is broken with [`Error::PacketTooLarge`].
+    ///
+    /// It must not be smaller than the max packet size advertised for
+    /// channels, otherwise sshd might send data packets exceeding it.
+    pub fn set_max_incoming_packet_size(&self, size: NonZeroU32) {
+        self.shared_data
+            .get_max_incoming_packet_size()
+            .set(size.get())
+    }
+
+    /// Wait until the connection is broken, return the fatal error that
+    /// broke it, or `None` if the read or write task panicked.
+    ///
+    /// Once the connection is broken, all pending operations on channels
+    /// fail with [`Error::ConnectionBroken`].
+    ///
+    /// This function is cancel safe.
+    pub async fn closed(&self) -> Option<Error> {
+        self.shared_data.get_cancellation_token().cancelled().await;
+
+        self.shared_data.fatal_error()
+    }
+
+    /// Close all channels and then the connection.
+    ///
+    /// Eof and close are sent to every channel opened, then it waits
+    /// until sshd closes all of them or `deadline` is reached, whichever
+    /// comes first.
+    ///
+    /// Channels not closed by then are forcibly closed: any pending
+    /// operation on them fails with [`Error::ConnectionBroken`] caused
+    /// by [`Error::ForcedShutdown`].
+    ///
+    /// Unlike [`ProxyClient::close`], it does not wait for handles of
+    /// the channels to be dropped.
+    ///
+    /// Return ids of the channels forcibly closed.
+    pub async fn shutdown(self, deadline: Instant) -> Result<Vec<u32>, Error> {
+        let shared_data = &self.shared_data;
+
+        shared_data.close_all_channels();
+
+        // On timeout, the remaining channels are forcibly closed.
+        timeout_at(deadline, shared_data.wait_for_all_channels_removed())
+            .await
+            .ok();
+
+        let forcibly_closed = shared_data.channel_ids();
+        if !forcibly_closed.is_empty() {
+            shared_data.report_fatal_error(Error::ForcedShutdown);
         }
+
+        // Stop the read and write task.
+        shared_data.get_cancellation_token().cancel();
+
+        self.close().await.map(|()| forcibly_closed)
     }
 
     pub async fn close(self) -> Result<(), Error> {
         drop(self.shared_data);
 
-        self.read_task.await??;
-        self.write_task.await??;
+        let read_res = self.read_task.wait().await?;
+        let write_res = self.write_task.wait().await?;
 
-        Ok(())
+        // If the error is not shared with any channel, then return
+        // the error as is.
+        read_res
+            .and(write_res)
+            .map_err(|err| Arc::try_unwrap(err).unwrap_or_else(Error::ConnectionBroken))
     }
 }
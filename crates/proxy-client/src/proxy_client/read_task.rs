@@ -1,39 +1,69 @@
 use std::{
     convert::TryInto,
-    num::NonZeroUsize,
     pin::Pin,
-    sync::{atomic::Ordering::Relaxed, Arc},
+    sync::{
+        atomic::{AtomicU32, Ordering::Relaxed},
+        Arc,
+    },
 };
 
 use bytes::{Bytes, BytesMut};
 use integer_hasher::IntMap;
 use ssh_format::from_bytes;
-use tokio::{io::AsyncRead, pin, select, spawn, task::JoinHandle};
+use tokio::{io::AsyncRead, pin, select};
 use tokio_io_utility::read_to_bytes_rng;
 
 use crate::{
     proxy_client::{
         channel::{
-            Completion, MpscBytesChannel, OpenChannelRequestedInner, OpenChannelRes, ProcessStatus,
+            MpscBytesChannel, OpenChannelRequestedInner, OpenChannelRes, ProcessStatus,
+            RequestOutcome,
         },
-        ChannelDataArenaArc, SharedData,
+        ChannelDataArenaArc, SharedData, DEFAULT_MAX_PACKET_SIZE,
     },
-    request::ChannelAdjustWindow,
+    request::{ChannelAdjustWindow, ChannelFailure},
     response::{ChannelRequest, ChannelResponse, ExtendedDataType, OpenConfirmation, Response},
     Error,
 };
 
-#[derive(Debug, Default)]
-struct PendingRequests {
-    pending: Option<NonZeroUsize>,
-    /// Has any request failed
-    has_failed: bool,
+/// RFC 4253 requires all implementations to be able to process packets
+/// of 35000 bytes, which is enough for everything other than data.
+const RFC4253_MIN_PACKET_SIZE: u32 = 35000;
+
+/// Default limit on incoming packets for channels with `max_packet_size`.
+pub(super) fn default_max_incoming_packet_size(max_packet_size: u32) -> u32 {
+    RFC4253_MIN_PACKET_SIZE.saturating_add(max_packet_size)
+}
+
+/// Limit on the size of incoming packets, excluding the length field.
+#[derive(Debug)]
+pub(super) struct MaxIncomingPacketSize(AtomicU32);
+
+impl Default for MaxIncomingPacketSize {
+    fn default() -> Self {
+        Self(AtomicU32::new(default_max_incoming_packet_size(
+            DEFAULT_MAX_PACKET_SIZE,
+        )))
+    }
+}
+
+impl MaxIncomingPacketSize {
+    pub(super) fn get(&self) -> u32 {
+        self.0.load(Relaxed)
+    }
+
+    pub(super) fn set(&self, size: u32) {
+        self.0.store(size, Relaxed)
+    }
 }
 
 #[derive(Debug)]
 struct ChannelIngoingData {
     outgoing_data_arena_arc: ChannelDataArenaArc,
 
+    /// Id of the channel allocated by sshd.
+    peer_channel_id: u32,
+
     /// Once this get into zero and `outgoing_data.receivers_count != 0`,
     /// then read task should send `extend_window_size_packet`.
     receiver_win_size: u32,
@@ -41,13 +71,13 @@ struct ChannelIngoingData {
     /// Check [`super::channel::ChannelState::extend_window_size`] for doc.
     extend_window_size: u32,
 
-    pending_requests: PendingRequests,
-
     rx: Option<Arc<MpscBytesChannel>>,
 
     stderr: Option<Arc<MpscBytesChannel>>,
 }
 
+/// Confirmed channels, keyed by our channel id, which is used by sshd
+/// as the recipient channel.
 #[derive(Debug, Default)]
 struct ChannelIngoingMap(IntMap<u32, ChannelIngoingData>);
 
@@ -55,8 +85,10 @@ impl ChannelIngoingMap {
     /// Insert a new entry with key `channel_id`.
     /// If such entry already exists, return an error.
     fn insert_new(&mut self, channel_id: u32, data: ChannelIngoingData) -> Result<(), Error> {
+        let peer_channel_id = data.peer_channel_id;
+
         if self.0.insert(channel_id, data).is_some() {
-            Err(Error::DuplicateSenderChannel(channel_id))
+            Err(Error::DuplicateSenderChannel(peer_channel_id))
         } else {
             Ok(())
         }
@@ -65,13 +97,13 @@ impl ChannelIngoingMap {
     fn get(&mut self, channel_id: u32) -> Result<&mut ChannelIngoingData, Error> {
         self.0
             .get_mut(&channel_id)
-            .ok_or(Error::InvalidSenderChannel(channel_id))
+            .ok_or(Error::InvalidRecipientChannel(channel_id))
     }
 
     fn remove(&mut self, channel_id: u32) -> Result<ChannelIngoingData, Error> {
         self.0
             .remove(&channel_id)
-            .ok_or(Error::InvalidSenderChannel(channel_id))
+            .ok_or(Error::InvalidRecipientChannel(channel_id))
     }
 
     fn is_empty(&self) -> bool {
@@ -93,6 +125,17 @@ fn handle_incoming_data(
 
     let cnt: u32 = bytes.len().try_into().unwrap_or(u32::MAX);
 
+    let receiver_win_size = &mut data.receiver_win_size;
+
+    if cnt > *receiver_win_size {
+        return Err(Error::ReceiverWindowExceeded {
+            size: cnt,
+            window: *receiver_win_size,
+        });
+    }
+
+    *receiver_win_size -= cnt;
+
     let data_receiver_channel = if is_rx {
         data.rx.as_ref()
     } else {
@@ -103,10 +146,6 @@ fn handle_incoming_data(
         channel.push_bytes(bytes);
     }
 
-    let receiver_win_size = &mut data.receiver_win_size;
-
-    *receiver_win_size = receiver_win_size.saturating_sub(cnt);
-
     let outgoing_data = &data.outgoing_data_arena_arc;
 
     // Extend receiver window if it is 0 and there are still
@@ -114,19 +153,16 @@ fn handle_incoming_data(
     if *receiver_win_size == 0 && outgoing_data.receivers_count.load(Relaxed) != 0 {
         let start = buffer.len();
 
-        ChannelAdjustWindow::new(
-            ChannelDataArenaArc::slot(outgoing_data),
-            data.extend_window_size,
-        )
-        .serialize_with_header(buffer, 0)
-        .unwrap();
+        ChannelAdjustWindow::new(data.peer_channel_id, data.extend_window_size)
+            .serialize_with_header(buffer, 0)
+            .unwrap();
 
         // After this op, buffer contains [0, start) which
         // contains the same content before extend_from_slice
         // and bytes contains `start..`
         let bytes = buffer.split_off(start).freeze();
 
-        shared_data.get_write_channel().push_bytes(bytes);
+        shared_data.get_write_scheduler().push_control(bytes);
 
         *receiver_win_size = data.extend_window_size;
     }
@@ -146,67 +182,54 @@ fn mark_eof(data: &mut ChannelIngoingData) {
 fn handle_request_response(
     hashmap: &mut ChannelIngoingMap,
     recipient_channel: u32,
-    success: bool,
+    outcome: RequestOutcome,
 ) -> Result<(), Error> {
-    let data = hashmap.get(recipient_channel)?;
-
-    let pending = &mut data.pending_requests.pending;
-
-    if pending.is_none() {
-        // Retreive the latest information of pending requests
-
-        *pending = data
-            .outgoing_data_arena_arc
-            .pending_requests
-            .retrieve_pending_requests();
+    // Replies are sent in the same order as the requests.
+    hashmap
+        .get(recipient_channel)?
+        .outgoing_data_arena_arc
+        .pending_requests
+        .report_request_outcome(outcome)
+}
 
-        // Reset has_failed
-        data.pending_requests.has_failed = false;
-    }
+pub(super) async fn create_read_task<R>(
+    rx: R,
+    shared_data: SharedData,
+    read_buffer_cap: usize,
+) -> Result<(), Arc<Error>>
+where
+    R: AsyncRead + Send,
+{
+    pin!(rx);
 
-    *pending = NonZeroUsize::new(
-        pending
-            .as_mut()
-            .ok_or(Error::UnexpectedRequestResponse)?
-            .get()
-            - 1,
-    );
+    let cancellation_token = shared_data.get_cancellation_token().clone();
+    let cancellation_guard = cancellation_token.clone().drop_guard();
 
-    data.pending_requests.has_failed |= !success;
+    let res = select! {
+        biased;
 
-    if pending.is_none() {
-        // All pending requests are done
+        // Stop immediately on forced shutdown or if the write task failed.
+        _ = cancellation_token.cancelled() => Ok(()),
 
-        let completion = if data.pending_requests.has_failed {
-            Completion::Failed
-        } else {
-            Completion::Success
-        };
+        res = create_read_task_inner(rx, &shared_data, read_buffer_cap) => {
+            res.map_err(|err| shared_data.report_fatal_error(err))
+        }
+    };
 
-        data.outgoing_data_arena_arc
-            .pending_requests
-            .report_request_completion(completion);
+    // Only cancel after the error is reported.
+    if res.is_ok() {
+        cancellation_guard.disarm();
     }
 
-    Ok(())
-}
-
-pub(super) fn create_read_task<R>(rx: R, shared_data: SharedData) -> JoinHandle<Result<(), Error>>
-where
-    R: AsyncRead + Send + 'static,
-{
-    spawn(async move {
-        pin!(rx);
-
-        create_read_task_inner(rx, shared_data).await
-    })
+    res
 }
 
 async fn create_read_task_inner(
     mut rx: Pin<&mut (dyn AsyncRead + Send)>,
-    shared_data: SharedData,
+    shared_data: &SharedData,
+    read_buffer_cap: usize,
 ) -> Result<(), Error> {
-    let mut buffer = BytesMut::with_capacity(1024);
+    let mut buffer = BytesMut::with_capacity(read_buffer_cap);
     let mut ingoing_channel_map = ChannelIngoingMap::default();
 
     let notified = shared_data.get_read_task_shutdown_notifier().notified();
@@ -214,15 +237,13 @@ async fn create_read_task_inner(
 
     notified.as_mut().enable();
 
-    let cancellation_guard = shared_data.get_cancellation_token().clone().drop_guard();
-
     loop {
         select! {
             biased;
 
             res = read_and_handle_one_packet(
                 rx.as_mut(),
-                &shared_data,
+                shared_data,
                 &mut buffer,
                 &mut ingoing_channel_map,
             ) => res?,
@@ -231,8 +252,6 @@ async fn create_read_task_inner(
         }
     }
 
-    cancellation_guard.disarm();
-
     Ok(())
 }
 
@@ -242,9 +261,22 @@ async fn read_and_handle_one_packet(
     buffer: &mut BytesMut,
     ingoing_channel_map: &mut ChannelIngoingMap,
 ) -> Result<(), Error> {
-    read_to_bytes_rng(&mut rx, buffer, 4..).await?;
+    // `buffer` might already contain the next packet, so only read
+    // what is missing from the header.
+    if buffer.len() < 4 {
+        read_to_bytes_rng(&mut rx, buffer, (4 - buffer.len())..).await?;
+    }
 
     let packet_len: u32 = from_bytes(&buffer[..4])?.0;
+
+    let limit = shared_data.get_max_incoming_packet_size().get();
+    if packet_len > limit {
+        return Err(Error::PacketTooLarge {
+            size: packet_len,
+            limit,
+        });
+    }
+
     let packet_len: usize = packet_len.try_into().unwrap();
 
     // Excluding the header (`u32`)
@@ -280,28 +312,45 @@ async fn read_and_handle_one_packet(
                 let OpenChannelRequestedInner {
                     init_receiver_win_size,
                     extend_window_size,
-                } = outgoing_data_arena_arc
-                    .state
-                    .set_channel_open_res(OpenChannelRes::Confirmed { max_packet_size })?;
+                    abandoned,
+                } = outgoing_data_arena_arc.state.set_channel_open_res(
+                    OpenChannelRes::Confirmed {
+                        max_packet_size,
+                        peer_channel_id: sender_channel,
+                    },
+                )?;
+
+                // Nobody would use the channel, so close it right away.
+                // It is removed once sshd closes it.
+                if abandoned {
+                    outgoing_data_arena_arc.send_close(
+                        shared_data,
+                        recipient_channel,
+                        sender_channel,
+                    );
+                }
 
                 let ingoing_data = ChannelIngoingData {
                     rx: outgoing_data_arena_arc.rx.clone(),
                     stderr: outgoing_data_arena_arc.stderr.clone(),
 
                     outgoing_data_arena_arc,
+                    peer_channel_id: sender_channel,
                     receiver_win_size: init_receiver_win_size,
                     extend_window_size,
-
-                    pending_requests: Default::default(),
                 };
 
-                ingoing_channel_map.insert_new(sender_channel, ingoing_data)?;
+                ingoing_channel_map.insert_new(recipient_channel, ingoing_data)?;
             }
             ChannelResponse::OpenFailure(failure) => {
-                shared_data
+                let OpenChannelRequestedInner { abandoned, .. } = shared_data
                     .get_channel_data(recipient_channel)?
                     .state
                     .set_channel_open_res(OpenChannelRes::Failed(failure))?;
+
+                if abandoned {
+                    shared_data.remove_channel_data(recipient_channel)?;
+                }
             }
 
             // Handle close of the channel
@@ -309,6 +358,17 @@ async fn read_and_handle_one_packet(
                 let mut data = ingoing_channel_map.remove(recipient_channel)?;
 
                 mark_eof(&mut data);
+
+                let channel_data = &data.outgoing_data_arena_arc;
+
+                channel_data.state.mark_closed();
+                // Wake up the writer so that it can find out the channel
+                // is closed.
+                channel_data.sender_window_size.wake_up();
+
+                // The channel would be freed once all references to it
+                // are dropped.
+                shared_data.remove_channel_data(recipient_channel)?;
             }
 
             // Handle data related responses
@@ -340,12 +400,16 @@ async fn read_and_handle_one_packet(
             ChannelResponse::Eof => mark_eof(ingoing_channel_map.get(recipient_channel)?),
 
             // Handle responses to requests
-            ChannelResponse::RequestSuccess => {
-                handle_request_response(ingoing_channel_map, recipient_channel, true)?
-            }
-            ChannelResponse::RequestFailure => {
-                handle_request_response(ingoing_channel_map, recipient_channel, false)?
-            }
+            ChannelResponse::RequestSuccess => handle_request_response(
+                ingoing_channel_map,
+                recipient_channel,
+                RequestOutcome::Success,
+            )?,
+            ChannelResponse::RequestFailure => handle_request_response(
+                ingoing_channel_map,
+                recipient_channel,
+                RequestOutcome::Failure,
+            )?,
 
             // Handle incoming requests from sshd (exit status)
             ChannelResponse::Request(request) => {
@@ -356,12 +420,24 @@ async fn read_and_handle_one_packet(
                     ChannelRequest::KilledBySignal(exit_signal) => {
                         ProcessStatus::ProcessKilled(exit_signal)
                     }
-                    _ => {
-                        return Err(Error::UnexpectedChannelState {
-                            expected_state:
-                                &"ChannelResponse::Request(StatusCode | KilledBySignal)",
-                            actual_state: "ChannelResponse::Request(Unknown)",
-                        })
+                    ChannelRequest::Unknown { want_reply } => {
+                        // Make sure the channel exists
+                        let data = ingoing_channel_map.get(recipient_channel)?;
+
+                        // Requests like keepalive@openssh.com expect a reply,
+                        // though any reply would do.
+                        if want_reply {
+                            let start = buffer.len();
+
+                            ChannelFailure::new(data.peer_channel_id)
+                                .serialize_with_header(buffer, 0)?;
+
+                            let bytes = buffer.split_off(start).freeze();
+
+                            shared_data.get_write_scheduler().push_control(bytes);
+                        }
+
+                        return Ok(());
                     }
                 };
 
@@ -381,3 +457,82 @@ async fn read_and_handle_one_packet(
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use tokio::io::{split, AsyncWriteExt};
+
+    use crate::proxy_client::test_utils::*;
+    use crate::{constants::*, Error, ProxyClient};
+
+    async fn assert_broken_by(client: &ProxyClient, check: impl FnOnce(&Error) -> bool) {
+        match client.closed().await {
+            Some(Error::ConnectionBroken(err)) => assert!(check(&err), "{:?}", err),
+            err => panic!("Unexpected error {:?}", err),
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_packet_too_large() {
+        let (client, mut peer) =
+            client(ProxyClient::builder().max_incoming_packet_size(NonZeroU32::new(100).unwrap()));
+
+        peer.write_u32(101).await.unwrap();
+
+        assert_broken_by(&client, |err| {
+            matches!(
+                err,
+                Error::PacketTooLarge {
+                    size: 101,
+                    limit: 100
+                }
+            )
+        })
+        .await;
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_receiver_window_exceeded() {
+        let (client, peer) =
+            client(ProxyClient::builder().channel_window_size(NonZeroU32::new(16).unwrap()));
+        let (mut peer_rx, mut peer_tx) = split(peer);
+
+        let peer = async move {
+            let channel_id = open_channel_sender(&read_packet(&mut peer_rx).await);
+
+            peer_tx
+                .write_all(&open_confirmation(channel_id, 0))
+                .await
+                .unwrap();
+
+            let mut data = channel_id.to_be_bytes().to_vec();
+            data.extend(17_u32.to_be_bytes());
+            data.extend([0; 17]);
+            peer_tx
+                .write_all(&packet(SSH_MSG_CHANNEL_DATA, &data))
+                .await
+                .unwrap();
+
+            (peer_rx, peer_tx)
+        };
+
+        let (stream, _peer) = tokio::join!(
+            client.open_direct_tcpip(("localhost", 80), ("127.0.0.1", 1)),
+            peer
+        );
+        let _stream = stream.unwrap();
+
+        assert_broken_by(&client, |err| {
+            matches!(
+                err,
+                Error::ReceiverWindowExceeded {
+                    size: 17,
+                    window: 16
+                }
+            )
+        })
+        .await;
+    }
+}
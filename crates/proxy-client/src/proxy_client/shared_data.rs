@@ -1,10 +1,20 @@
-use std::sync::Arc;
+use std::{
+    num::NonZeroUsize,
+    sync::{Arc, OnceLock},
+};
 
-use tokio::sync::Notify;
+use tokio::{
+    pin, select,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore},
+};
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    proxy_client::channel::{ChannelData, MpscBytesChannel},
+    proxy_client::{
+        channel::{ChannelData, WriteScheduler},
+        read_task::MaxIncomingPacketSize,
+        write_task::WriteCounters,
+    },
     Error,
 };
 
@@ -14,23 +24,146 @@ const BITARRAY_LEN: usize = LEN / (usize::BITS as usize);
 type ChannelDataArena = concurrent_arena::Arena<ChannelData, BITARRAY_LEN, LEN>;
 pub(super) type ChannelDataArenaArc = concurrent_arena::ArenaArc<ChannelData, BITARRAY_LEN, LEN>;
 
+/// Max packet size advertised to sshd for every channel,
+/// same as the default of openssh.
+pub(super) const DEFAULT_MAX_PACKET_SIZE: u32 = 32 * 1024;
+
+/// Initial receiver window size of every channel,
+/// same as the default of openssh.
+pub(super) const DEFAULT_WINDOW_SIZE: u32 = 64 * DEFAULT_MAX_PACKET_SIZE;
+
+/// Parameters used when opening new channels.
+#[derive(Copy, Clone, Debug)]
+pub(super) struct ChannelConfig {
+    /// Initial receiver window size and the number of bytes to extend
+    /// the window by once it is exhausted.
+    pub(super) window_size: u32,
+    pub(super) max_packet_size: u32,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            window_size: DEFAULT_WINDOW_SIZE,
+            max_packet_size: DEFAULT_MAX_PACKET_SIZE,
+        }
+    }
+}
+
+/// Limit on the number of channels alive.
+#[derive(Debug)]
+pub(super) struct ChannelLimit {
+    semaphore: Arc<Semaphore>,
+    max_channels: Option<NonZeroUsize>,
+}
+
+impl Default for ChannelLimit {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl ChannelLimit {
+    /// * `max_channels` - `None` means unlimited.
+    pub(super) fn new(max_channels: Option<NonZeroUsize>) -> Self {
+        let permits = max_channels.map_or(Semaphore::MAX_PERMITS, NonZeroUsize::get);
+
+        Self {
+            semaphore: Arc::new(Semaphore::new(permits)),
+            max_channels,
+        }
+    }
+
+    /// Wait until the number of channels is below the limit.
+    pub(super) async fn acquire(&self) -> OwnedSemaphorePermit {
+        self.semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed")
+    }
+
+    pub(super) fn try_acquire(&self) -> Result<OwnedSemaphorePermit, Error> {
+        self.semaphore
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Error::ChannelLimitReached)
+    }
+
+    pub(super) fn max_channels(&self) -> Option<NonZeroUsize> {
+        self.max_channels
+    }
+
+    pub(super) fn channel_count(&self) -> usize {
+        let permits = self
+            .max_channels
+            .map_or(Semaphore::MAX_PERMITS, NonZeroUsize::get);
+
+        permits - self.semaphore.available_permits()
+    }
+}
+
 #[derive(Debug, Default, Clone)]
 pub(super) struct SharedData(Arc<SharedDataInner>);
 
 impl SharedData {
-    pub(super) fn get_write_channel(&self) -> &MpscBytesChannel {
-        &self.0.write_channel
+    pub(super) fn new(
+        channel_config: ChannelConfig,
+        max_incoming_packet_size: u32,
+        max_channels: Option<NonZeroUsize>,
+    ) -> Self {
+        let inner = SharedDataInner {
+            channel_config,
+            channel_limit: ChannelLimit::new(max_channels),
+            ..Default::default()
+        };
+        inner.max_incoming_packet_size.set(max_incoming_packet_size);
+
+        Self(Arc::new(inner))
+    }
+
+    pub(super) fn get_channel_config(&self) -> &ChannelConfig {
+        &self.0.channel_config
+    }
+
+    pub(super) fn get_channel_limit(&self) -> &ChannelLimit {
+        &self.0.channel_limit
+    }
+
+    pub(super) fn get_write_scheduler(&self) -> &WriteScheduler {
+        &self.0.write_scheduler
+    }
+
+    pub(super) fn get_write_counters(&self) -> &WriteCounters {
+        &self.0.write_counters
+    }
+
+    pub(super) fn get_max_incoming_packet_size(&self) -> &MaxIncomingPacketSize {
+        &self.0.max_incoming_packet_size
     }
 
     pub(super) fn insert_channel_data(&self, channel_data: ChannelData) -> ChannelDataArenaArc {
-        self.0.channel_data_arena.insert(channel_data)
+        let arena_arc = self.0.channel_data_arena.insert(channel_data);
+
+        // The connection might be broken before the channel is inserted,
+        // in which case it is missed by `report_fatal_error`.
+        if let Some(err) = self.0.fatal_error.get() {
+            arena_arc.abort(err);
+        }
+
+        arena_arc
     }
 
     pub(super) fn remove_channel_data(&self, slot: u32) -> Result<ChannelDataArenaArc, Error> {
-        self.0
+        let res = self
+            .0
             .channel_data_arena
             .remove(slot)
-            .ok_or(Error::InvalidRecipientChannel(slot))
+            .ok_or(Error::InvalidRecipientChannel(slot));
+
+        self.0.channel_removed_notifier.notify_waiters();
+
+        res
     }
 
     pub(super) fn get_channel_data(&self, slot: u32) -> Result<ChannelDataArenaArc, Error> {
@@ -47,6 +180,80 @@ impl SharedData {
     pub(super) fn get_cancellation_token(&self) -> &CancellationToken {
         &self.0.cancellation_token
     }
+
+    /// Return all channels in the arena, including the ones being opened.
+    fn channels(&self) -> impl Iterator<Item = ChannelDataArenaArc> + '_ {
+        let arena = &self.0.channel_data_arena;
+
+        (0..arena.len() * (LEN as u32)).filter_map(move |slot| arena.get(slot))
+    }
+
+    /// Return ids of all channels in the arena.
+    pub(super) fn channel_ids(&self) -> Vec<u32> {
+        self.channels()
+            .map(|channel_data| ChannelDataArenaArc::slot(&channel_data))
+            .collect()
+    }
+
+    /// Send eof and close to every channel confirmed.
+    pub(super) fn close_all_channels(&self) {
+        for channel_data in self.channels() {
+            if let Some(peer_channel_id) = channel_data.state.peer_channel_id() {
+                let channel_id = ChannelDataArenaArc::slot(&channel_data);
+
+                channel_data.send_eof(self, channel_id, peer_channel_id);
+                channel_data.send_close(self, channel_id, peer_channel_id);
+            }
+        }
+    }
+
+    /// Wait until every channel is removed from the arena, or
+    /// the cancellation token is cancelled.
+    pub(super) async fn wait_for_all_channels_removed(&self) {
+        let cancellation_token = self.get_cancellation_token();
+
+        loop {
+            let notified = self.0.channel_removed_notifier.notified();
+            pin!(notified);
+
+            // Register for notification before checking the arena,
+            // so that the removal in between cannot be missed.
+            notified.as_mut().enable();
+
+            if self.channels().next().is_none() {
+                break;
+            }
+
+            select! {
+                _ = notified => (),
+                _ = cancellation_token.cancelled() => break,
+            }
+        }
+    }
+
+    /// Return the fatal error that broke the connection, if any.
+    pub(super) fn fatal_error(&self) -> Option<Error> {
+        self.0
+            .fatal_error
+            .get()
+            .map(|err| Error::ConnectionBroken(err.clone()))
+    }
+
+    /// Record `err` as the fatal error unless there is already one,
+    /// then wake up every channel so that pending operations fail with it.
+    ///
+    /// Must be called before the cancellation token is cancelled.
+    pub(super) fn report_fatal_error(&self, err: Error) -> Arc<Error> {
+        let err = Arc::new(err);
+
+        let fatal_error = self.0.fatal_error.get_or_init(|| err.clone());
+
+        for channel_data in self.channels() {
+            channel_data.abort(fatal_error);
+        }
+
+        err
+    }
 }
 
 impl Drop for SharedData {
@@ -59,21 +266,92 @@ impl Drop for SharedData {
             //
             // which means that we should request shutdown now.
             //
-            // Once write_channel is marked as eof, write task
+            // Once write_scheduler is marked as eof, write task
             // would exit as soon as all data is flushed.
             //
             // Then it would notify read task to also shutdown.
-            self.get_write_channel().mark_eof();
+            self.get_write_scheduler().mark_eof();
         }
     }
 }
 
 #[derive(Debug, Default)]
 struct SharedDataInner {
-    write_channel: MpscBytesChannel,
+    channel_config: ChannelConfig,
+    channel_limit: ChannelLimit,
+
+    write_scheduler: WriteScheduler,
+    write_counters: WriteCounters,
+    max_incoming_packet_size: MaxIncomingPacketSize,
     channel_data_arena: ChannelDataArena,
+    channel_removed_notifier: Notify,
 
     read_task_shutdown_notifier: Notify,
 
     cancellation_token: CancellationToken,
+
+    fatal_error: OnceLock<Arc<Error>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures_util::FutureExt;
+
+    fn channel_limit(max_channels: usize) -> ChannelLimit {
+        ChannelLimit::new(Some(NonZeroUsize::new(max_channels).unwrap()))
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn test_acquire_waits_for_release() {
+        let limit = channel_limit(1);
+
+        let permit = limit.acquire().await;
+
+        let acquire = limit.acquire();
+        tokio::pin!(acquire);
+        assert!((&mut acquire).now_or_never().is_none());
+
+        drop(permit);
+        assert!(acquire.now_or_never().is_some());
+    }
+
+    #[test]
+    fn test_try_acquire_limit_reached() {
+        let limit = channel_limit(2);
+
+        let _permits = [limit.try_acquire().unwrap(), limit.try_acquire().unwrap()];
+
+        assert!(matches!(
+            limit.try_acquire(),
+            Err(Error::ChannelLimitReached)
+        ));
+    }
+
+    #[test]
+    fn test_channel_count() {
+        let limit = channel_limit(3);
+        assert_eq!(limit.max_channels(), NonZeroUsize::new(3));
+        assert_eq!(limit.channel_count(), 0);
+
+        let permit1 = limit.try_acquire().unwrap();
+        let permit2 = limit.acquire().now_or_never().unwrap();
+        assert_eq!(limit.channel_count(), 2);
+
+        drop(permit1);
+        assert_eq!(limit.channel_count(), 1);
+
+        drop(permit2);
+        assert_eq!(limit.channel_count(), 0);
+    }
+
+    #[test]
+    fn test_unlimited_channel_count() {
+        let limit = ChannelLimit::default();
+        assert_eq!(limit.max_channels(), None);
+
+        let _permit = limit.try_acquire().unwrap();
+        assert_eq!(limit.channel_count(), 1);
+    }
 }
@@ -0,0 +1,56 @@
+//! Helpers to play sshd in tests.
+
+use std::convert::TryInto;
+
+use tokio::io::{duplex, split, AsyncReadExt, DuplexStream, ReadHalf};
+
+use crate::{constants::*, ProxyClient, ProxyClientBuilder};
+
+/// Create a client connected to the returned stream, which plays sshd.
+pub(super) fn client(builder: &ProxyClientBuilder) -> (ProxyClient, DuplexStream) {
+    let (client_stream, peer_stream) = duplex(1 << 20);
+    let (rx, tx) = split(client_stream);
+
+    (builder.build(rx, tx), peer_stream)
+}
+
+/// Read one packet without the header.
+pub(super) async fn read_packet(peer: &mut ReadHalf<DuplexStream>) -> Vec<u8> {
+    let len = peer.read_u32().await.unwrap();
+    let mut packet = vec![0; len as usize];
+    peer.read_exact(&mut packet).await.unwrap();
+    packet
+}
+
+/// Return the packet with header, padding length and `packet_type`.
+pub(super) fn packet(packet_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = ((body.len() + 2) as u32).to_be_bytes().to_vec();
+    packet.extend([0, packet_type]);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Return the recipient channel of `packet` read by [`read_packet`].
+pub(super) fn recipient_channel(packet: &[u8]) -> u32 {
+    u32::from_be_bytes(packet[2..6].try_into().unwrap())
+}
+
+/// Return the sender channel of the open channel `packet` read by
+/// [`read_packet`].
+pub(super) fn open_channel_sender(packet: &[u8]) -> u32 {
+    assert_eq!(packet[1], SSH_MSG_CHANNEL_OPEN);
+
+    // Skip the channel type
+    let type_len = recipient_channel(packet) as usize;
+
+    u32::from_be_bytes(packet[6 + type_len..][..4].try_into().unwrap())
+}
+
+/// Return the open confirmation packet.
+pub(super) fn open_confirmation(recipient_channel: u32, sender_channel: u32) -> Vec<u8> {
+    let mut body = Vec::new();
+    for int in [recipient_channel, sender_channel, 1 << 20, 32768] {
+        body.extend(int.to_be_bytes());
+    }
+    packet(SSH_MSG_CHANNEL_OPEN_CONFIRMATION, &body)
+}
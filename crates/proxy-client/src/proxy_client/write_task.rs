@@ -1,53 +1,205 @@
-use std::{num::NonZeroUsize, pin::Pin};
+use std::{
+    io::{self, IoSlice},
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
 
 use scopeguard::defer;
-use tokio::{io::AsyncWrite, pin, spawn, task::JoinHandle};
+use tokio::{
+    io::AsyncWrite,
+    pin, select,
+    time::{timeout_at, Instant},
+};
 use tokio_io_utility::{write_all_bytes, ReusableIoSlices};
 
 use crate::{proxy_client::SharedData, Error};
 
-pub(super) fn create_write_task<W>(
+/// Configuration for coalescing small writes into fewer syscalls.
+///
+/// After data is available, the write task keeps collecting more data
+/// until either `min_bytes` is buffered or `max_delay` has elapsed,
+/// trading latency for throughput.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct WriteCoalescing {
+    /// Maximum time to wait for more data before writing.
+    pub max_delay: Duration,
+    /// Write immediately once at least this many bytes are buffered.
+    pub min_bytes: usize,
+}
+
+/// Statistics of the write task of a [`super::ProxyClient`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct WriteStats {
+    /// Number of successful write syscalls.
+    pub write_syscalls: u64,
+    /// Number of bytes written.
+    pub bytes_written: u64,
+    /// Number of batches of packets written, each of which might
+    /// take multiple syscalls.
+    pub batches: u64,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct WriteCounters {
+    write_syscalls: AtomicU64,
+    bytes_written: AtomicU64,
+    batches: AtomicU64,
+}
+
+impl WriteCounters {
+    pub(super) fn get(&self) -> WriteStats {
+        WriteStats {
+            write_syscalls: self.write_syscalls.load(Relaxed),
+            bytes_written: self.bytes_written.load(Relaxed),
+            batches: self.batches.load(Relaxed),
+        }
+    }
+
+    fn record_write(&self, poll: Poll<io::Result<usize>>) -> Poll<io::Result<usize>> {
+        if let Poll::Ready(Ok(n)) = &poll {
+            self.write_syscalls.fetch_add(1, Relaxed);
+            self.bytes_written.fetch_add(*n as u64, Relaxed);
+        }
+        poll
+    }
+}
+
+/// [`AsyncWrite`] wrapper that updates [`WriteCounters`].
+struct CountingWriter<'a> {
+    inner: Pin<&'a mut (dyn AsyncWrite + Send)>,
+    counters: &'a WriteCounters,
+}
+
+// `inner` is already pinned, so moving `CountingWriter` is fine.
+impl Unpin for CountingWriter<'_> {}
+
+impl AsyncWrite for CountingWriter<'_> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.inner.as_mut().poll_write(cx, buf);
+        self.counters.record_write(poll)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let poll = self.inner.as_mut().poll_write_vectored(cx, bufs);
+        self.counters.record_write(poll)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.as_mut().poll_shutdown(cx)
+    }
+}
+
+pub(super) async fn create_write_task<W>(
     tx: W,
     shared_data: SharedData,
     reusable_io_slice_cap: NonZeroUsize,
-) -> JoinHandle<Result<(), Error>>
+    coalescing: Option<WriteCoalescing>,
+) -> Result<(), Arc<Error>>
 where
-    W: AsyncWrite + Send + 'static,
+    W: AsyncWrite + Send,
 {
-    spawn(async move {
-        pin!(tx);
+    pin!(tx);
+
+    let cancellation_token = shared_data.get_cancellation_token().clone();
+    let cancellation_guard = cancellation_token.clone().drop_guard();
+
+    let res = select! {
+        biased;
+
+        // Stop immediately on forced shutdown or if the read task failed.
+        _ = cancellation_token.cancelled() => Ok(()),
+
+        res = create_write_task_inner(tx, &shared_data, reusable_io_slice_cap, coalescing) => {
+            res.map_err(|err| shared_data.report_fatal_error(err))
+        }
+    };
 
-        create_write_task_inner(tx, shared_data, reusable_io_slice_cap).await
-    })
+    // Only cancel after the error is reported.
+    if res.is_ok() {
+        cancellation_guard.disarm();
+    }
+
+    res
 }
 
 async fn create_write_task_inner(
-    mut tx: Pin<&mut (dyn AsyncWrite + Send)>,
-    shared_data: SharedData,
+    tx: Pin<&mut (dyn AsyncWrite + Send)>,
+    shared_data: &SharedData,
     reusable_io_slice_cap: NonZeroUsize,
+    coalescing: Option<WriteCoalescing>,
 ) -> Result<(), Error> {
-    let write_channel = shared_data.get_write_channel();
+    let write_scheduler = shared_data.get_write_scheduler();
+    let counters = shared_data.get_write_counters();
     let mut reusable_io_slice = ReusableIoSlices::new(reusable_io_slice_cap);
 
+    let mut tx = CountingWriter {
+        inner: tx,
+        counters,
+    };
+    let mut tx = Pin::new(&mut tx);
+
     let mut buffer = Vec::new();
+    let mut more = Vec::new();
 
     defer! {
         shared_data.get_read_task_shutdown_notifier().notify_one();
     }
 
-    let cancellation_guard = shared_data.get_cancellation_token().clone().drop_guard();
-
     loop {
-        write_channel.wait_for_data(&mut buffer).await;
+        write_scheduler.wait_for_data(&mut buffer).await;
         if buffer.is_empty() {
             // Eof
             break;
         }
 
+        if let Some(WriteCoalescing {
+            max_delay,
+            min_bytes,
+        }) = coalescing
+        {
+            let deadline = Instant::now() + max_delay;
+            let mut len: usize = buffer.iter().map(|bytes| bytes.len()).sum();
+
+            while len < min_bytes {
+                if timeout_at(deadline, write_scheduler.wait_for_data(&mut more))
+                    .await
+                    .is_err()
+                    || more.is_empty()
+                {
+                    // Timeout or eof, write out what has been buffered.
+                    break;
+                }
+
+                len += more.iter().map(|bytes| bytes.len()).sum::<usize>();
+                buffer.append(&mut more);
+            }
+        }
+
+        counters.batches.fetch_add(1, Relaxed);
         write_all_bytes(tx.as_mut(), &mut buffer, &mut reusable_io_slice).await?;
     }
 
-    cancellation_guard.disarm();
-
     Ok(())
 }
@@ -52,3 +52,34 @@ impl Session {
         )
     }
 }
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub(crate) struct DirectTcpip<'a> {
+    host: &'a str,
+    port: u32,
+    originator_ip: &'a str,
+    originator_port: u32,
+}
+
+impl<'a> DirectTcpip<'a> {
+    pub(crate) fn new(
+        sender_channel: u32,
+        initial_windows_size: u32,
+        max_packet_size: u32,
+        (host, port): (&'a str, u32),
+        (originator_ip, originator_port): (&'a str, u32),
+    ) -> Request<OpenChannel<DirectTcpip<'a>>> {
+        OpenChannel::new(
+            &"direct-tcpip",
+            sender_channel,
+            initial_windows_size,
+            max_packet_size,
+            Self {
+                host,
+                port,
+                originator_ip,
+                originator_port,
+            },
+        )
+    }
+}
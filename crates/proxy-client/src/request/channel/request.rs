@@ -9,7 +9,8 @@ use crate::{constants::*, NonZeroByteSlice};
 pub(crate) struct ChannelRequest<T> {
     recipient_channel: u32,
     request_type: &'static &'static str,
-    want_reply: bool,
+    /// SSH encodes boolean as a single byte.
+    want_reply: u8,
     request_specific_data: T,
 }
 
@@ -24,7 +25,7 @@ impl<T: Serialize> ChannelRequest<T> {
             Self {
                 recipient_channel,
                 request_type,
-                want_reply: true,
+                want_reply: 1,
                 request_specific_data,
             },
         )
@@ -34,14 +35,14 @@ impl<T: Serialize> ChannelRequest<T> {
 #[derive(Clone, Debug, Serialize)]
 pub(crate) struct PassEnv<'a> {
     name: Cow<'a, str>,
-    value: Cow<'a, str>,
+    value: Cow<'a, NonZeroByteSlice>,
 }
 
 impl<'a> PassEnv<'a> {
     pub(crate) fn new(
         recipient_channel: u32,
         name: Cow<'a, str>,
-        value: Cow<'a, str>,
+        value: Cow<'a, NonZeroByteSlice>,
     ) -> Request<ChannelRequest<Self>> {
         ChannelRequest::new(recipient_channel, &"env", Self { name, value })
     }
@@ -59,14 +60,36 @@ impl<'a> ExecCmd<'a> {
     }
 }
 
-#[derive(Copy, Clone, Debug, Serialize)]
-pub(crate) struct RequestSubsystem(&'static &'static str);
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct RequestSubsystem<'a>(Cow<'a, NonZeroByteSlice>);
 
-impl RequestSubsystem {
+impl<'a> RequestSubsystem<'a> {
     pub(crate) fn new(
         recipient_channel: u32,
-        subsystem: &'static &'static str,
+        subsystem: Cow<'a, NonZeroByteSlice>,
     ) -> Request<ChannelRequest<Self>> {
         ChannelRequest::new(recipient_channel, &"subsystem", Self(subsystem))
     }
 }
+
+/// Start the login shell of the user.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub(crate) struct RequestShell;
+
+impl RequestShell {
+    pub(crate) fn new(recipient_channel: u32) -> Request<ChannelRequest<Self>> {
+        ChannelRequest::new(recipient_channel, &"shell", Self)
+    }
+}
+
+/// Reply to a channel request from sshd that is not supported.
+#[derive(Copy, Clone, Debug, Serialize)]
+pub(crate) struct ChannelFailure {
+    recipient_channel: u32,
+}
+
+impl ChannelFailure {
+    pub(crate) fn new(recipient_channel: u32) -> Request<ChannelFailure> {
+        Request::new(SSH_MSG_CHANNEL_FAILURE, Self { recipient_channel })
+    }
+}
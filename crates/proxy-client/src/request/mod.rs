@@ -87,3 +87,46 @@ impl SerOutput for SliceOutput<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::borrow::Cow;
+
+    use crate::{constants::*, NonZeroByteSlice};
+
+    #[test]
+    fn test_data_transfer_header() {
+        let mut buffer = BytesMut::new();
+
+        let header = DataTransfer::create_header(1, 3, &mut buffer).unwrap();
+
+        // The packet length includes the data following the header.
+        assert_eq!(
+            &header[..],
+            [0, 0, 0, 13, 0, SSH_MSG_CHANNEL_DATA, 0, 0, 0, 1, 0, 0, 0, 3]
+        );
+    }
+
+    #[test]
+    fn test_channel_request_want_reply_single_byte() {
+        let mut buffer = BytesMut::new();
+
+        let cmd = NonZeroByteSlice::new(b"ls").unwrap();
+        ExecCmd::new(1, Cow::Borrowed(cmd))
+            .serialize_with_header(&mut buffer, 0)
+            .unwrap();
+
+        let mut expected = vec![0, SSH_MSG_CHANNEL_REQUEST, 0, 0, 0, 1];
+        expected.extend([0, 0, 0, 4]);
+        expected.extend_from_slice(b"exec");
+        // want_reply
+        expected.push(1);
+        expected.extend([0, 0, 0, 2]);
+        expected.extend_from_slice(b"ls");
+
+        assert_eq!(&buffer[..4], (expected.len() as u32).to_be_bytes());
+        assert_eq!(&buffer[4..], expected);
+    }
+}
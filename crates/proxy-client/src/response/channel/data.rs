@@ -1,4 +1,4 @@
-use std::fmt;
+use std::{convert::TryInto, fmt};
 
 use bytes::Bytes;
 use serde::{de::Deserializer, Deserialize};
@@ -30,6 +30,18 @@ impl fmt::Display for ExtendedDataType {
 
 impl ExtendedDataType {
     pub(in crate::response) fn from_bytes(bytes: Bytes) -> Result<(Self, Bytes), Error> {
-        Ok((deserialize(&bytes)?, bytes.slice(4..)))
+        Ok((deserialize(&bytes)?, data_from_bytes(bytes.slice(4..))?))
     }
 }
+
+/// Strip the length prefix of the data.
+pub(in crate::response) fn data_from_bytes(bytes: Bytes) -> Result<Bytes, Error> {
+    let len: u32 = deserialize(&bytes)?;
+    let len: usize = len.try_into().unwrap();
+
+    if len > bytes.len() - 4 {
+        return Err(Error::InvalidResponse(&"Data is shorter than its length"));
+    }
+
+    Ok(bytes.slice(4..(4 + len)))
+}
@@ -3,21 +3,26 @@ use std::borrow::Cow;
 use compact_str::CompactString;
 use serde::{de::Deserializer, Deserialize};
 
-use crate::error::ErrMsg;
+use crate::{error::ErrMsg, response::deserialize_ssh_bool};
 
+/// Exit status of the remote process.
 #[derive(Copy, Clone, Debug, Deserialize)]
 #[repr(transparent)]
-pub(crate) struct ExitStatus(pub u32);
+pub struct ExitStatus(pub u32);
 
+/// The remote process is killed by a signal.
 #[derive(Clone, Debug, Deserialize)]
-pub(crate) struct ExitSignal {
+pub struct ExitSignal {
     pub signal_name: SignalName,
+    #[serde(deserialize_with = "deserialize_ssh_bool")]
     pub core_dumped: bool,
     pub err_msg: ErrMsg,
 }
 
+/// Name of the signal, as defined in RFC 4254.
 #[derive(Clone, Debug)]
-pub(crate) enum SignalName {
+#[non_exhaustive]
+pub enum SignalName {
     Abrt,
     Alrm,
     Fpe,
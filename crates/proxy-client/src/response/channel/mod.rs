@@ -5,7 +5,7 @@ use serde::Deserialize;
 use crate::Error;
 
 mod exit_status;
-pub(crate) use exit_status::*;
+pub use exit_status::*;
 
 mod data;
 pub(crate) use data::*;
@@ -7,7 +7,7 @@ use ssh_format::from_bytes;
 use crate::{
     response::{
         channel::{ExitSignal, ExitStatus},
-        deserialize,
+        deserialize, deserialize_ssh_bool,
     },
     Error,
 };
@@ -16,6 +16,7 @@ use crate::{
 struct ChannelRequestHeader<'a> {
     #[serde(borrow)]
     pub(crate) request_type: Cow<'a, str>,
+    #[serde(deserialize_with = "deserialize_ssh_bool")]
     pub(crate) want_reply: bool,
 }
 
@@ -23,7 +24,7 @@ struct ChannelRequestHeader<'a> {
 pub(crate) enum ChannelRequest {
     StatusCode(ExitStatus),
     KilledBySignal(ExitSignal),
-    Unknown,
+    Unknown { want_reply: bool },
 }
 
 impl ChannelRequest {
@@ -34,7 +35,9 @@ impl ChannelRequest {
         Ok(match header.request_type.as_ref() {
             "exit-status" => StatusCode(deserialize(data)?),
             "exit-signal" => KilledBySignal(deserialize(data)?),
-            _ => Unknown,
+            _ => Unknown {
+                want_reply: header.want_reply,
+            },
         })
     }
 }
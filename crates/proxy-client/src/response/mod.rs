@@ -14,8 +14,16 @@ where
     Ok(ssh_format::from_bytes(s)?.0)
 }
 
+/// Unlike the mux protocol, SSH encodes boolean as a single byte.
+fn deserialize_ssh_bool<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(<u8 as Deserialize>::deserialize(deserializer)? != 0)
+}
+
 mod channel;
-pub(crate) use channel::*;
+pub use channel::*;
 
 #[derive(Clone, Debug, IntoStaticStr)]
 pub(crate) enum Response {
@@ -81,7 +89,7 @@ impl ChannelResponse {
             SSH_MSG_CHANNEL_WINDOW_ADJUST => Ok(BytesAdjust {
                 bytes_to_add: deserialize(&bytes)?,
             }),
-            SSH_MSG_CHANNEL_DATA => Ok(Data(bytes)),
+            SSH_MSG_CHANNEL_DATA => Ok(Data(data_from_bytes(bytes)?)),
             SSH_MSG_CHANNEL_EXTENDED_DATA => {
                 let (data_type, data) = ExtendedDataType::from_bytes(bytes)?;
                 Ok(ExtendedData { data_type, data })
@@ -98,3 +106,87 @@ impl ChannelResponse {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the packet body of `packet_type` sent to channel 1.
+    fn channel_packet(packet_type: u8, body: &[u8]) -> Bytes {
+        let mut packet = vec![0, packet_type, 0, 0, 0, 1];
+        packet.extend_from_slice(body);
+        packet.into()
+    }
+
+    fn string(s: &[u8]) -> Vec<u8> {
+        let mut bytes = (s.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(s);
+        bytes
+    }
+
+    fn channel_response(packet: Bytes) -> ChannelResponse {
+        match Response::from_bytes(packet).unwrap() {
+            Response::ChannelResponse {
+                channel_response,
+                recipient_channel: 1,
+            } => channel_response,
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_data_strips_length() {
+        let packet = channel_packet(SSH_MSG_CHANNEL_DATA, &string(b"abc"));
+
+        match channel_response(packet) {
+            ChannelResponse::Data(data) => assert_eq!(&data[..], b"abc"),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_data_shorter_than_length() {
+        let mut body = 4_u32.to_be_bytes().to_vec();
+        body.extend_from_slice(b"abc");
+
+        assert!(matches!(
+            Response::from_bytes(channel_packet(SSH_MSG_CHANNEL_DATA, &body)),
+            Err(Error::InvalidResponse(_))
+        ));
+    }
+
+    #[test]
+    fn test_extended_data_strips_length() {
+        let mut body = SSH_EXTENDED_DATA_STDERR.to_be_bytes().to_vec();
+        body.extend(string(b"err"));
+
+        match channel_response(channel_packet(SSH_MSG_CHANNEL_EXTENDED_DATA, &body)) {
+            ChannelResponse::ExtendedData {
+                data_type: ExtendedDataType::Stderr,
+                data,
+            } => assert_eq!(&data[..], b"err"),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    #[test]
+    fn test_exit_signal_single_byte_bool() {
+        let mut body = string(b"exit-signal");
+        // want_reply
+        body.push(0);
+        body.extend(string(b"KILL"));
+        // core_dumped
+        body.push(1);
+        body.extend(string(b"killed"));
+        body.extend(string(b"en"));
+
+        match channel_response(channel_packet(SSH_MSG_CHANNEL_REQUEST, &body)) {
+            ChannelResponse::Request(ChannelRequest::KilledBySignal(exit_signal)) => {
+                assert!(matches!(exit_signal.signal_name, SignalName::Kill));
+                assert!(exit_signal.core_dumped);
+                assert_eq!(exit_signal.err_msg.get(), ("killed", "en"));
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+}
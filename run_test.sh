@@ -30,7 +30,12 @@ test_mux_client() {
     start_ssh_tester
 
     if [ $# -lt 1 ]; then
-        cargo test test_unordered -- --nocapture
+        cargo test --all-features test_unordered -- --nocapture
+        cargo test --features master master::tests -- --nocapture
+        cargo test --features master,monitor monitor::tests -- --nocapture
+        cargo test --features master,pool pool::tests -- --nocapture
+        cargo test --features blocking,master test_blocking_stub -- --nocapture
+        cargo test --features master graceful_shutdown::tests -- --nocapture
         cargo test test_request_stop_listening -- --nocapture
 
         if [ -e $ControlPath ]; then